@@ -0,0 +1,65 @@
+// Tight DRW loop with the dyn display of State against the concrete DisplayBuffer of a Core.
+// Run with `cargo bench --bench draw`.
+
+use std::hint::black_box;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use chip8_lib::{
+    Beeper, Core, DebugBeeper, DebugKeypad, DebugTimer, Display, DisplayBuffer, Keypad, State, Timer, DEFAULT_FONT,
+};
+
+const STEPS: usize = 2_000_000;
+
+// I = font "0", then DRW V0, V1, 5 and jump back to the DRW forever
+const PROGRAM: [u8; 6] = [0xA0, 0x50, 0xD0, 0x15, 0x12, 0x02];
+
+fn run<D: Display, T: Timer, B: Beeper, K: Keypad>(core: &mut Core<D, T, B, K>) -> Duration {
+    core.initialize(&PROGRAM, &DEFAULT_FONT);
+    let start = Instant::now();
+    for _ in 0..STEPS {
+        black_box(core.execute()).unwrap();
+    }
+    start.elapsed()
+}
+
+fn report(name: &str, elapsed: Duration, baseline: Duration) {
+    println!(
+        "{:<40} {:>8.1} ns/step  {:>5.2}x",
+        name,
+        elapsed.as_nanos() as f64 / STEPS as f64,
+        baseline.as_secs_f64() / elapsed.as_secs_f64()
+    );
+}
+
+fn timer() -> DebugTimer {
+    DebugTimer { value: 0 }
+}
+
+fn beeper() -> DebugBeeper {
+    DebugBeeper { value: 0 }
+}
+
+fn keypad() -> DebugKeypad {
+    DebugKeypad { currently_pressed: None }
+}
+
+fn main() {
+    let mut state: State = Core::new(
+        Arc::new(Mutex::new(DisplayBuffer::new())),
+        Arc::new(Mutex::new(timer())),
+        Arc::new(Mutex::new(beeper())),
+        Arc::new(Mutex::new(keypad())),
+    );
+    let dyn_shared = run(&mut state);
+
+    let mut locked = Core::new(Arc::new(Mutex::new(DisplayBuffer::new())), timer(), beeper(), keypad());
+    let concrete_shared = run(&mut locked);
+
+    let mut owned = Core::new(DisplayBuffer::new(), timer(), beeper(), keypad());
+    let concrete_owned = run(&mut owned);
+
+    report("State (dyn Display, Mutex)", dyn_shared, dyn_shared);
+    report("Core<Arc<Mutex<DisplayBuffer>>, ..>", concrete_shared, dyn_shared);
+    report("Core<DisplayBuffer, ..>", concrete_owned, dyn_shared);
+}
@@ -1,4 +1,18 @@
-use core::panic;
+#![cfg_attr(not(feature = "std"), no_std)]
+
+// Without the std feature the interpreter is available as Core, which owns its peripherals. The State with peripherals
+// shared as Arc<Mutex<_>>, the thread local rng and file loading need std.
+extern crate alloc;
+
+use alloc::borrow::Cow;
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+#[cfg(feature = "std")]
 use std::sync::{Arc, Mutex};
 
 const MEM_SIZE: usize = 0xFFF + 1; // 4KiB
@@ -10,6 +24,9 @@ const FONT_CHARACTER_BYTES: usize = 5;
 // for compability with older programs
 const PROGRAM_START: usize = 0x200;
 
+// the original COSMAC VIP had 12 levels, 16 is what most interpreters use
+const STACK_SIZE: usize = 16;
+
 pub const DEFAULT_FONT: [u8; 80] = [
     0xF0, 0x90, 0x90, 0x90, 0xF0, // 0
     0x20, 0x60, 0x20, 0x20, 0x70, // 1
@@ -54,6 +71,52 @@ pub trait Display {
     fn width(&self) -> usize;
     fn height(&self) -> usize;
     fn clear(&mut self);
+
+    /// Returns (width, height) of the display.
+    fn dimensions(&self) -> (usize, usize) {
+        (self.width(), self.height())
+    }
+
+    ///
+    /// Returns true if the pixel at display position (x,y) is turned on.
+    ///
+    /// The default implementation always returns false, displays that keep track of their pixels should override it.
+    #[allow(unused_variables)]
+    fn get_pixel(&self, x: usize, y: usize) -> bool {
+        false
+    }
+
+    ///
+    /// Returns what [Display::modify] would return for the same arguments, without changing the display.
+    /// Lets analysis tools and bots predict the VF outcome of a DRW.
+    ///
+    /// The default implementation always returns false, displays that keep track of their pixels should override it.
+    #[allow(unused_variables)]
+    fn would_collide(&self, sprite: &[u8], n: u8, x: u8, y: u8) -> bool {
+        false
+    }
+
+    ///
+    /// Switches the display resolution, e.g. between the 64x32 lo-res and the 128x64 SUPER-CHIP hi-res mode.
+    /// The display is cleared and width and height must report the new dimensions afterwards.
+    ///
+    /// The default implementation does nothing, for displays with a fixed resolution.
+    #[allow(unused_variables)]
+    fn set_resolution(&mut self, width: usize, height: usize) {}
+
+    ///
+    /// Returns the display position at which [Display::modify] places the top left pixel of a sprite started at
+    /// (x,y), or None if the sprite is not drawn at all. The interpreter uses it to report [DrawInfo].
+    /// A position outside of the dimensions is reported as a sprite without visible rows.
+    ///
+    /// The default implementation wraps the start position around the dimensions.
+    fn sprite_origin(&self, x: u8, y: u8) -> Option<(usize, usize)> {
+        let (width, height) = self.dimensions();
+        if width == 0 || height == 0 {
+            return None;
+        }
+        Some((x as usize % width, y as usize % height))
+    }
 }
 
 ///
@@ -63,9 +126,26 @@ pub trait Display {
 pub trait Keypad {
     ///
     /// Returns `Some<_>` if a key is *currently* pressed, `None` otherwise. The Some contains the pressed key as an `u8` (0x0 .. 0xF)
+    ///
+    /// The interpreter only uses the lower nibble, a misbehaving keypad returning e.g. 0x1A is read as key 0xA.
     fn get_pressed_key(&self) -> Option<u8>;
 }
 
+///
+/// Returns the label of a key (0x0 .. 0xF) as shown on the keypad, '0'..'9' and 'A'..'F'. Returns `None` for other values.
+pub fn key_label(key: u8) -> Option<char> {
+    if key > 0xF {
+        return None;
+    }
+    char::from_digit(key as u32, 16).map(|c| c.to_ascii_uppercase())
+}
+
+///
+/// Returns the key for a keypad label, the inverse of [key_label]. Lower case labels are accepted as well.
+pub fn key_from_label(label: char) -> Option<u8> {
+    label.to_digit(16).map(|key| key as u8)
+}
+
 pub trait Beeper {
     ///
     /// Starts the Beeper. The Beeper counter initialized by time must be decremented 60 times per second.
@@ -77,6 +157,20 @@ pub trait Beeper {
     /// * 'time' - value that the internal counter is initialized with
     ///
     fn start(&mut self, time: u8);
+
+    ///
+    /// Returns the current value of the internal counter.
+    ///
+    /// The default implementation always returns 0, beepers that want to be observed by the interpreter should override it.
+    fn remaining(&self) -> u8 {
+        0
+    }
+
+    ///
+    /// Decrements the internal counter by one if it is not zero. Used by hosts that let the interpreter drive the timers.
+    ///
+    /// The default implementation does nothing, for beepers that count down on their own.
+    fn tick(&mut self) {}
 }
 
 /// The chip8 timer is a 8-Bit timer that decrements its internal value 60 times a second. Chip8 has two timers.
@@ -88,10 +182,319 @@ pub trait Timer {
     fn get(&self) -> u8;
 }
 
-// choosing trait objects to make gui stuff easier
-// making everything threadsafe so that IO stuff can run in different threads
-pub struct State {
-    memory: Vec<u8>,
+/// Source of the random bytes of Rand (CXNN).
+pub trait RandomSource {
+    fn next_byte(&mut self) -> u8;
+}
+
+#[cfg(feature = "std")]
+impl RandomSource for rand::rngs::ThreadRng {
+    fn next_byte(&mut self) -> u8 {
+        rand::Rng::gen(self)
+    }
+}
+
+/// Deterministic xorshift64* generator, the same seed always produces the same bytes on every platform.
+/// Not suitable for anything but emulation.
+pub struct SeededRng {
+    state: u64,
+}
+
+impl SeededRng {
+    pub fn new(seed: u64) -> Self {
+        // xorshift gets stuck at 0
+        let state = if seed == 0 { 0x9E37_79B9_7F4A_7C15 } else { seed };
+        Self { state }
+    }
+}
+
+impl RandomSource for SeededRng {
+    fn next_byte(&mut self) -> u8 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        (self.state.wrapping_mul(0x2545_F491_4F6C_DD1D) >> 56) as u8
+    }
+}
+
+/// The memory of a [State]. Implement this to trace memory accesses or to map IO into the address space.
+///
+/// Addresses passed to read and write are always below size, the State wraps them around before.
+pub trait Memory {
+    fn read(&self, addr: usize) -> u8;
+    fn write(&mut self, addr: usize, value: u8);
+    /// Returns the number of bytes, must be a power of two.
+    fn size(&self) -> usize;
+    /// Changes the number of bytes to size (a power of two), see [State::set_memory_size]. The content below the new
+    /// size is kept, new bytes are set to fill.
+    fn resize(&mut self, size: usize, fill: u8);
+    ///
+    /// Returns the whole memory if it is stored as one slice, so [State::memory] can borrow it instead of copying it
+    /// with [Memory::read]. Backends that trace reads keep the default.
+    fn as_slice(&self) -> Option<&[u8]> {
+        None
+    }
+}
+
+/// Plain memory backed by a byte vector, the default [Memory] of a [State].
+pub struct LinearMemory {
+    bytes: Vec<u8>,
+}
+
+impl LinearMemory {
+    /// Creates zeroed memory with size bytes, size must be a power of two.
+    pub fn new(size: usize) -> Self {
+        debug_assert!(size.is_power_of_two());
+        Self { bytes: vec![0; size] }
+    }
+}
+
+impl Default for LinearMemory {
+    fn default() -> Self {
+        Self::new(MEM_SIZE)
+    }
+}
+
+impl Memory for LinearMemory {
+    fn read(&self, addr: usize) -> u8 {
+        self.bytes[addr]
+    }
+
+    fn write(&mut self, addr: usize, value: u8) {
+        self.bytes[addr] = value;
+    }
+
+    fn size(&self) -> usize {
+        self.bytes.len()
+    }
+
+    fn resize(&mut self, size: usize, fill: u8) {
+        debug_assert!(size.is_power_of_two());
+        self.bytes.resize(size, fill);
+    }
+
+    fn as_slice(&self) -> Option<&[u8]> {
+        Some(&self.bytes)
+    }
+}
+
+/// Size of the memory of a [State].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MemorySize {
+    /// 4KiB, the classic chip8 memory
+    #[default]
+    Classic,
+    /// 64KiB as used by XO-CHIP
+    XoChip,
+}
+
+impl MemorySize {
+    /// Returns the size in bytes.
+    pub fn bytes(self) -> usize {
+        match self {
+            MemorySize::Classic => MEM_SIZE,
+            MemorySize::XoChip => 0xFFFF + 1,
+        }
+    }
+}
+
+/// Behaviors that differ between chip8 interpreters. The default is what this crate always did.
+///
+/// For an overview see: <https://chip8.gulrak.net/#quirk5> and the quirks test ROM by Timendus.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Quirks {
+    /// 8XY6/8XYE shift VY and store the result in VX (COSMAC VIP). Otherwise VX is shifted in place (CHIP-48, SUPER-CHIP).
+    pub shift_uses_vy: bool,
+    /// FX1E sets VF to 1 if I + VX leaves the memory (above 0x0FFF, or 0xFFFF with 64KiB) and to 0 otherwise (Amiga interpreter).
+    /// Otherwise VF is not affected.
+    pub add_i_sets_overflow: bool,
+    /// DXY0 in lo-res draws 16 rows of 8 pixels (Octo). Otherwise it draws nothing, like the original interpreters that
+    /// only know DXYN with N >= 1. In hi-res DXY0 always draws a 16x16 sprite.
+    pub lores_dxy0_draws_16_rows: bool,
+    /// How FX55/FX65 change I, see [IndexIncrement].
+    pub load_store_increment: IndexIncrement,
+    /// Skips over F000 NNNN as a whole, since it is 4 bytes long (XO-CHIP). Otherwise skips always skip 2 bytes and land
+    /// on the NNNN of a skipped F000. F000 NNNN itself is not executed by this crate yet.
+    pub skip_long_instructions: bool,
+    /// 8XY4, 8XY5, 8XY6, 8XY7 and 8XYE write VF before the result, so with X = F the result replaces the flag. This is
+    /// what this crate did before writing the flag last, which all common interpreters do. Only needed for ROMs that were
+    /// tuned against older versions of this crate and use VF as the target of arithmetic.
+    pub vf_order_legacy: bool,
+    /// DXYN sets VF to 1 on a collision and leaves it unchanged otherwise, like a few rare interpreters. Otherwise every
+    /// DXYN writes VF, 1 on a collision and 0 without.
+    pub draw_sets_vf_only_on_collision: bool,
+}
+
+impl Quirks {
+    /// The behavior of the original COSMAC VIP interpreter.
+    pub fn cosmac_vip() -> Self {
+        Self {
+            shift_uses_vy: true,
+            load_store_increment: IndexIncrement::XPlus1,
+            ..Self::default()
+        }
+    }
+
+    /// The behavior of SUPER-CHIP 1.1 on the HP48.
+    pub fn super_chip() -> Self {
+        Self {
+            shift_uses_vy: false,
+            lores_dxy0_draws_16_rows: true,
+            load_store_increment: IndexIncrement::None,
+            ..Self::default()
+        }
+    }
+}
+
+/// How FX55 (RegDump) and FX65 (RegLoad) change I after accessing V0..VX, see [Quirks::load_store_increment].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum IndexIncrement {
+    /// I is left unchanged (CHIP-48, SUPER-CHIP 1.1)
+    #[default]
+    None,
+    /// I is incremented by X (SUPER-CHIP 1.0)
+    X,
+    /// I is incremented by X + 1 and points behind the last accessed byte (COSMAC VIP, XO-CHIP)
+    XPlus1,
+}
+
+/// Frequencies [State::run_for] converts elapsed time with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Clock {
+    /// instructions per second
+    pub cpu_hz: u32,
+    /// timer ticks per second, 60 on all common platforms
+    pub timer_hz: u32,
+}
+
+impl Default for Clock {
+    /// 700 instructions per second, which suits most classic ROMs, and 60 timer ticks per second.
+    fn default() -> Self {
+        Self {
+            cpu_hz: 700,
+            timer_hz: 60,
+        }
+    }
+}
+
+/// Errors that can occur while executing an instruction. The state is left as it was before the failing fetch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExecutionError {
+    /// The pc points to an odd address while an aligned pc is required. Contains the pc.
+    MisalignedPc(usize),
+    /// The opcode does not decode to an instruction. Contains the opcode.
+    InvalidOpcode(u16),
+    /// An access would leave the memory and strict memory checks are enabled. Contains the first address out of bounds.
+    MemoryOutOfBounds(usize),
+    /// BCD would write below 0x200 into the interpreter area with the font while strict memory checks are enabled.
+    /// Usually I was not set before the BCD. Contains I.
+    ReservedMemoryWrite(usize),
+    /// The instruction belongs to a family disabled with [State::set_enabled_instructions]. Contains the opcode.
+    DisabledInstruction(u16),
+    /// A call would exceed the stack limit.
+    StackOverflow,
+    /// A return was executed with an empty stack.
+    StackUnderflow,
+}
+
+impl fmt::Display for ExecutionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExecutionError::MisalignedPc(pc) => write!(f, "pc {:#05x} is not 2-byte aligned", pc),
+            ExecutionError::InvalidOpcode(op_code) => write!(f, "invalid opcode {:#06x}", op_code),
+            ExecutionError::MemoryOutOfBounds(addr) => write!(f, "memory access out of bounds at {:#05x}", addr),
+            ExecutionError::ReservedMemoryWrite(addr) => write!(f, "BCD writes to reserved memory at {:#05x}", addr),
+            ExecutionError::DisabledInstruction(op_code) => write!(f, "instruction {:#06x} is disabled", op_code),
+            ExecutionError::StackOverflow => write!(f, "stack overflow"),
+            ExecutionError::StackUnderflow => write!(f, "return with empty stack"),
+        }
+    }
+}
+
+impl core::error::Error for ExecutionError {}
+
+/// What happens when a return finds an empty stack or a call exceeds the stack limit, see [State::set_stack_policy].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum StackPolicy {
+    /// Panic. A return with an empty stack panicked before there was a policy, a call never did since the stack had no limit
+    Panic,
+    /// Return [ExecutionError::StackUnderflow] or [ExecutionError::StackOverflow]
+    Error,
+    /// Keep running: a return with an empty stack is skipped, a call on a full stack jumps without pushing the return address
+    #[default]
+    Saturate,
+}
+
+/// Returned by the closure passed to [State::run_debug] to control the run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DebugAction {
+    /// Execute the instruction and keep running
+    Continue,
+    /// Stop before the instruction, calling run_debug again resumes at it
+    Pause,
+    /// Stop before the instruction, e.g. because a condition failed
+    Abort,
+}
+
+/// What happened in a successful [State::execute].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StepOutcome {
+    /// The instruction was executed, DRW returns [StepOutcome::Drew] instead
+    Executed,
+    /// WaitKey found no pressed key for [State::set_max_wait_spins] executions in a row. The host should stop
+    /// executing and pump input, nothing changes until a key is pressed
+    StalledOnKey,
+    /// A DRW was executed, see [DrawInfo]
+    Drew(DrawInfo),
+}
+
+/// Details of an executed DRW, see [StepOutcome::Drew].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DrawInfo {
+    /// a pixel was turned off, the value of VF
+    pub collided: bool,
+    /// number of sprite rows on the display
+    pub rows_drawn: u8,
+    /// part of the sprite was cut off at the bottom or the right edge, or the display did not draw it at all,
+    /// see [Display::sprite_origin]
+    pub clipped: bool,
+}
+
+/// Why [State::run_debug] stopped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DebugStop {
+    /// The instruction budget was used up
+    BudgetExhausted,
+    /// The closure returned [DebugAction::Pause]
+    Paused,
+    /// The closure returned [DebugAction::Abort]
+    Aborted,
+}
+
+/// Result of [State::run_debug].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DebugRun {
+    /// number of executed instructions
+    pub steps: usize,
+    pub stop: DebugStop,
+    /// stack depth when the run stopped, a nonzero depth usually means a CALL without RET
+    pub stack_depth: usize,
+}
+
+/// Handler for opcodes that do not decode, see [Core::set_extension_handler].
+pub type ExtensionHandler<D, T, B, K> = Box<dyn FnMut(&mut Core<D, T, B, K>, u16) -> bool>;
+
+/// The interpreter: memory, registers and the execution of instructions, with the peripherals it owns.
+///
+/// Owning the peripherals needs neither std nor locks, e.g. `Core<DisplayBuffer, _, _, _>` on an embedded target.
+/// With std, [State] is the Core with peripherals shared as `Arc<Mutex<_>>`, so that the host can access them from
+/// other threads.
+///
+/// A Core with a concrete display also avoids the dynamic dispatch of State. In the DRW loop of `benches/draw.rs` an
+/// owned [DisplayBuffer] runs about 5% faster than State, almost all of it from not locking. The dispatch itself is
+/// not measurable next to the pixel loop of [Display::modify].
+pub struct Core<D, T, B, K> {
+    memory: Box<dyn Memory>,
     // u16 should be enough for the usual 4k, but usize should be better for indexing the memory vector
     pc: usize,
     index_reg: u16,
@@ -99,29 +502,89 @@ pub struct State {
     // the 16 general purpose registers
     gp_registers: [u8; 16],
 
-    rng: RngWrapper,
+    quirks: Quirks,
 
-    display: Arc<Mutex<dyn Display>>,
-    delay_timer: Arc<Mutex<dyn Timer>>,
-    sound_timer: Arc<Mutex<dyn Beeper>>,
-    keypad: Arc<Mutex<dyn Keypad>>,
-}
+    rng: Box<dyn RandomSource>,
 
-// wrapper for rng, rand does not work (easily?) with wasm.
-// TODO support different generators depending on platform
-struct RngWrapper {
-    generator: rand::rngs::ThreadRng,
-}
+    display: D,
+    delay_timer: T,
+    sound_timer: B,
+    keypad: K,
 
-impl RngWrapper{
-    fn new() -> Self{
-        Self{generator: rand::thread_rng()}
-    }
+    // instructions are 2 bytes, an odd pc usually means a bad jump
+    require_aligned_pc: bool,
+    // error instead of wrapping around at the end of memory
+    strict_memory: bool,
+    // None means the stack can grow without limit
+    stack_limit: Option<usize>,
+    stack_policy: StackPolicy,
+    // families that may be executed, the others return DisabledInstruction
+    enabled_instructions: InstructionSet,
+    // read the keypad once per frame, see set_keypad_latch
+    keypad_latch: bool,
+    latched_key: Option<u8>,
+    // key mask of every frame since start_recording, None if not recording
+    input_recording: Option<Vec<u16>>,
+    // key masks replayed instead of the keypad, replay_frame indexes the current frame
+    replay_inputs: Vec<u16>,
+    replay_frame: usize,
+    // WaitKey executions without a key in a row, StalledOnKey is returned from max_wait_spins on
+    wait_spins: usize,
+    max_wait_spins: Option<usize>,
+    // value of memory that was never written by initialize or the program
+    memory_fill: u8,
+    // where initialize put the font, SetFontI points here
+    font_address: usize,
+    // error instead of skipping opcodes that do not decode
+    invalid_opcode_errors: bool,
+    // gets opcodes that do not decode first, returns true if it executed them
+    extension_handler: Option<ExtensionHandler<D, T, B, K>>,
 
-    fn generate_random_byte(&mut self) -> u8{
-        rand::Rng::gen(&mut self.generator)
-    }
+    // called with true when the beeper starts and with false when it runs out
+    sound_hook: Option<Box<dyn FnMut(bool)>>,
+    // SetSoundTimer values below this are treated as 0
+    min_sound_timer: u8,
+    // called at every DRW, for front-ends emulating the VIP's screen blanking
+    draw_interference_hook: Option<Box<dyn FnMut()>>,
+    // called with the address and value when an instruction writes into the loaded program
+    program_write_hook: Option<Box<dyn FnMut(usize, u8)>>,
+    // length of the program loaded by initialize
+    program_len: usize,
+    // (address, opcode) of the last fetch, for execute_decoded
+    fetched: (usize, u16),
+
+    // sum of Instruction::cycle_cost of all executed instructions
+    machine_cycles: u64,
+
+    clear_display_on_init: bool,
+    // (delay, sound) the timers start with on every initialize, see with_timers
+    boot_timers: (u8, u8),
+
+    // set by every colliding draw, unlike VF it is only reset by clear_frame_collision
+    frame_collision: bool,
+    // the display changed since the last present_done
+    needs_present: bool,
+    // 128x64 SUPER-CHIP mode, selects the DXY0 behavior
+    hires: bool,
+
+    // called with the new value and the mnemonic whenever an instruction writes VF
+    vf_watch: Option<Box<dyn FnMut(u8, &'static str)>>,
+
+    // bit i is set once Vi was written since the last initialize
+    written_registers: u16,
+    // called with the register and the instruction address when a register is read before it was written
+    uninitialized_read_hook: Option<Box<dyn FnMut(u8, usize)>>,
+
+    // (address, opcode) of every executed instruction, None if logging is disabled
+    opcode_log: Option<Vec<(usize, u16)>>,
+
+    // time run_for has not turned into instructions and timer ticks yet, in nanoseconds times the frequency
+    cpu_phase: u128,
+    timer_phase: u128,
+    // timer_hz per step_with_timers, a tick is due every cpu_hz
+    step_timer_phase: u64,
 }
+
 // Some mock structs for testing and debugging
 // ----------------------------------------------------------------
 pub struct DebugDisplay {
@@ -144,9 +607,7 @@ impl Display for DebugDisplay {
         self.width
     }
 
-    fn clear(&mut self) {
-        return;
-    }
+    fn clear(&mut self) {}
 }
 
 pub struct DebugKeypad {
@@ -165,6 +626,14 @@ impl Beeper for DebugBeeper {
     fn start(&mut self, time: u8) {
         self.value = time;
     }
+
+    fn remaining(&self) -> u8 {
+        self.value
+    }
+
+    fn tick(&mut self) {
+        self.value = self.value.saturating_sub(1);
+    }
 }
 
 pub struct DebugTimer {
@@ -180,16 +649,109 @@ impl Timer for DebugTimer {
     }
 }
 
+// Null peripherals that do nothing, for running the cpu without any IO, see State::headless
+/// A display that ignores all draws. It reports the regular 64x32 dimensions.
+pub struct NullDisplay;
+
+impl Display for NullDisplay {
+    #[allow(unused_variables)]
+    fn modify(&mut self, sprite: &[u8], n: u8, x: u8, y: u8) -> bool {
+        false
+    }
+
+    fn width(&self) -> usize {
+        64
+    }
+
+    fn height(&self) -> usize {
+        32
+    }
+
+    fn clear(&mut self) {}
+}
+
+/// A delay timer that always reads 0.
+pub struct NullTimer;
+
+impl Timer for NullTimer {
+    #[allow(unused_variables)]
+    fn set(&mut self, val: u8) {}
+
+    fn get(&self) -> u8 {
+        0
+    }
+}
+
+/// A beeper that never beeps.
+pub struct NullBeeper;
+
+impl Beeper for NullBeeper {
+    #[allow(unused_variables)]
+    fn start(&mut self, time: u8) {}
+}
+
+/// A keypad without pressed keys.
+pub struct NullKeypad;
+
+impl Keypad for NullKeypad {
+    fn get_pressed_key(&self) -> Option<u8> {
+        None
+    }
+}
+
 // ----------------------------------------------------------------
 
 // A proper display implementation
 // ----------------------------------------------------------------
 
+/// Returned when a buffer passed to a [DisplayBuffer] does not match the display dimensions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BufferSizeError {
+    pub expected: usize,
+    pub actual: usize,
+}
+
+impl fmt::Display for BufferSizeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "expected a buffer of {} bytes, got {}", self.expected, self.actual)
+    }
+}
+
+impl core::error::Error for BufferSizeError {}
+
+/// How [DisplayBuffer::modify] treats a sprite start position outside of the display.
+/// This is independent of the clipping of the pixels that run off the edge, which always happens.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SpriteStart {
+    /// The start position wraps around, x = 68 draws at x = 4 on a 64 pixel wide display
+    #[default]
+    Wrap,
+    /// Sprites starting outside of the display are not drawn at all
+    Clip,
+}
+
+/// Unit of the scroll distances of [DisplayBuffer::scroll_down] and friends.
+/// The scroll instructions count hi-res (128x64) pixels, interpreters differ in what that means in lo-res.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ScrollUnits {
+    /// XO-CHIP: in lo-res every unit is a whole (doubled) lo-res pixel, so a scroll always moves n pixels of the current resolution
+    #[default]
+    XoChip,
+    /// SUPER-CHIP: units are hi-res pixels, so lo-res scrolls by half pixels. Odd lo-res distances are rounded down
+    SuperChip,
+}
+
 /// This struct implements the Display trait. Modify only affects the display vec. The display is 64x32 pixels.
 pub struct DisplayBuffer {
     pub display: Vec<bool>,
     display_width: usize,
     display_height: usize,
+    // (min x, min y, max x, max y) of the pixels flipped by the last modify
+    last_draw_bounds: Option<(usize, usize, usize, usize)>,
+    sprite_start: SpriteStart,
+    scroll_units: ScrollUnits,
+    // the pixels as of the last present_dirty
+    presented: Vec<bool>,
 }
 
 impl DisplayBuffer {
@@ -203,6 +765,10 @@ impl DisplayBuffer {
             display,
             display_width,
             display_height,
+            last_draw_bounds: None,
+            sprite_start: SpriteStart::default(),
+            scroll_units: ScrollUnits::default(),
+            presented: vec![false; display_width * display_height],
         }
     }
 
@@ -213,354 +779,2153 @@ impl DisplayBuffer {
     pub fn get_height(&self) -> usize {
         self.display_height
     }
-}
 
-// TODO: check if the result may be reversed for the display values
-fn u8_to_bool_array(byte: u8) -> [bool; 8] {
-    let mut bool_array = [false; 8];
-    for i in 0..=7 {
-        let mask = 0b10000000 >> i;
-        bool_array[i] = (byte & mask) != 0;
+    ///
+    /// Packs the display into bytes, 8 pixels per byte. The pixels are in row-major order, the first pixel is the
+    /// most significant bit. A 64x32 display packs into 256 bytes.
+    pub fn as_packed_bytes(&self) -> Vec<u8> {
+        self.display
+            .chunks(8)
+            .map(|pixels| {
+                pixels
+                    .iter()
+                    .enumerate()
+                    .fold(0, |byte, (i, on)| byte | ((*on as u8) << (7 - i)))
+            })
+            .collect()
     }
-    // kinda cool that this works in rust (returning array). Probably just copy
-    bool_array
-}
 
-impl Display for DisplayBuffer {
-    fn modify(&mut self, sprite: &[u8], n: u8, x: u8, y: u8) -> bool {
-        // must be set to true if a pixel of the display is turned off
-        let mut result_flag = false;
+    ///
+    /// Restores the display from bytes created by [DisplayBuffer::as_packed_bytes].
+    ///
+    /// Returns an error and leaves the display unchanged if bytes does not have `width * height / 8` bytes.
+    pub fn load_packed_bytes(&mut self, bytes: &[u8]) -> Result<(), BufferSizeError> {
+        let expected = self.display.len().div_ceil(8);
+        if bytes.len() != expected {
+            return Err(BufferSizeError {
+                expected,
+                actual: bytes.len(),
+            });
+        }
+        for (i, pixel) in self.display.iter_mut().enumerate() {
+            *pixel = bytes[i / 8] & (0b10000000 >> (i % 8)) != 0;
+        }
+        Ok(())
+    }
 
-        // should wrap, x = 5 should be the same as x = 68
-        let actual_x = x % self.display_width as u8;
-        let actual_y = y % self.display_height as u8;
+    ///
+    /// Creates a display with the given pixels, e.g. to set up a screen for collision tests without replaying draws.
+    ///
+    /// Returns an error if pixels does not have `width * height` entries.
+    ///
+    /// # Arguments
+    /// * 'width' - width of the display in pixels
+    /// * 'height' - height of the display in pixels
+    /// * 'pixels' - the pixels in row-major order, true is on
+    pub fn from_grid(width: usize, height: usize, pixels: Vec<bool>) -> Result<Self, BufferSizeError> {
+        if pixels.len() != width * height {
+            return Err(BufferSizeError {
+                expected: width * height,
+                actual: pixels.len(),
+            });
+        }
+        let mut buffer = Self::new();
+        buffer.set_resolution(width, height);
+        buffer.display = pixels;
+        Ok(buffer)
+    }
 
-        // sprites should be clipped
-        // sprites are 8 pixels wide (each u8 of the sprite) and n pixels tall
-        // the sprite just XORs each bit with the corresponding display pixel
+    ///
+    /// Converts the display into a 1 bit per pixel bitmap for golden-image tests. Rows are stored top to bottom, each row
+    /// starts at a new byte and the leftmost pixel is the most significant bit, which is the raster format of PBM (P4).
+    /// Unlike [DisplayBuffer::as_packed_bytes] rows are padded to whole bytes if the width is not a multiple of 8.
+    pub fn to_bitmap(&self) -> Vec<u8> {
+        let row_bytes = self.display_width.div_ceil(8);
+        let mut bitmap = vec![0; row_bytes * self.display_height];
+        for (i, _) in self.display.iter().enumerate().filter(|(_, on)| **on) {
+            let (x, y) = (i % self.display_width, i / self.display_width);
+            bitmap[y * row_bytes + x / 8] |= 0b10000000 >> (x % 8);
+        }
+        bitmap
+    }
 
-        for line in 0..n {
-            
-            let line_bools = u8_to_bool_array(sprite[line as usize]);
-            //println!("\t{:?}", line_bools);
-            /*
-            line_bools.clone().map(|i| {
-                if i{
-                    print!("█");
-                } else {
-                    print!(" ");
-                }
+    ///
+    /// Creates a display from a bitmap in the format of [DisplayBuffer::to_bitmap].
+    ///
+    /// Returns an error if the bitmap does not have `ceil(width / 8) * height` bytes.
+    ///
+    /// # Arguments
+    /// * 'width' - width of the display in pixels
+    /// * 'height' - height of the display in pixels
+    /// * 'bitmap' - the rows of the bitmap
+    pub fn from_bitmap(width: usize, height: usize, bitmap: &[u8]) -> Result<Self, BufferSizeError> {
+        let row_bytes = width.div_ceil(8);
+        let expected = row_bytes * height;
+        if bitmap.len() != expected {
+            return Err(BufferSizeError {
+                expected,
+                actual: bitmap.len(),
             });
+        }
+        let mut buffer = Self::new();
+        buffer.set_resolution(width, height);
+        for (i, pixel) in buffer.display.iter_mut().enumerate() {
+            let (x, y) = (i % width, i / width);
+            *pixel = bitmap[y * row_bytes + x / 8] & (0b10000000 >> (x % 8)) != 0;
+        }
+        Ok(buffer)
+    }
 
-            println!("");
-            */
-
-            if (actual_y + line) as usize >= self.display_height {
-                // sprite should clip so we are finished
-                return result_flag;
-            }
+    ///
+    /// Returns the number of pixels that are on, e.g. to check the screen of a ROM after a number of frames.
+    pub fn lit_pixel_count(&self) -> usize {
+        self.display.iter().filter(|lit| **lit).count()
+    }
 
-            for (i, b) in line_bools.iter().enumerate() {
-                // drawing should clip
-                if actual_x as usize + i < self.display_width {
-                    let index =
-                        actual_x as usize + i + self.display_width * (line + actual_y) as usize;
-                    let old = self.display[index];
-                    // note that != is the same as a logical XOR
-                    self.display[index] = self.display[index] != *b;
+    ///
+    /// Converts the display into RGBA pixels, 4 bytes per pixel in row-major order, ready to upload as a texture.
+    ///
+    /// # Arguments
+    /// * 'on' - color of lit pixels
+    /// * 'off' - color of unlit pixels
+    pub fn to_rgba(&self, on: [u8; 4], off: [u8; 4]) -> Vec<u8> {
+        self.display.iter().flat_map(|lit| if *lit { on } else { off }).collect()
+    }
 
-                    // if the bit was set a pixel was flipped
-                    if *b  && old{
-                        result_flag = true;
-                    }
-                }
+    ///
+    /// Calls f with (x, y, on) for every pixel that changed since the last call, then remembers the current pixels.
+    /// Renderers on constrained targets can use this to only redraw what changed. The first call reports every pixel
+    /// that is on. After a resolution change the host has to redraw the (blank) display as a whole.
+    pub fn present_dirty<F: FnMut(usize, usize, bool)>(&mut self, mut f: F) {
+        for (i, (on, presented)) in self.display.iter().zip(self.presented.iter_mut()).enumerate() {
+            if on != presented {
+                f(i % self.display_width, i / self.display_width, *on);
+                *presented = *on;
             }
         }
-        result_flag
     }
 
-    fn height(&self) -> usize {
-        self.display_height
+    /// Sets how scroll distances are interpreted in lo-res, see [ScrollUnits].
+    pub fn set_scroll_units(&mut self, scroll_units: ScrollUnits) {
+        self.scroll_units = scroll_units;
     }
 
-    fn width(&self) -> usize {
-        self.display_width
+    /// Scrolls the display down by n units (00CN), see [ScrollUnits]. Rows scrolled in at the top are off.
+    pub fn scroll_down(&mut self, n: usize) {
+        let rows = self.scroll_distance(n) as isize;
+        self.shift(0, rows);
     }
 
-    fn clear(&mut self) {
-        self.display.fill(false);
+    /// Scrolls the display up by n units (XO-CHIP 00DN), see [ScrollUnits]. Rows scrolled in at the bottom are off.
+    pub fn scroll_up(&mut self, n: usize) {
+        let rows = self.scroll_distance(n) as isize;
+        self.shift(0, -rows);
     }
-}
-// ----------------------------------------------------------------
 
-impl State {
-    pub fn new(
-        display: Arc<Mutex<dyn Display + Send>>,
-        delay_timer: Arc<Mutex<dyn Timer + Send>>,
-        sound_timer: Arc<Mutex<dyn Beeper + Send>>,
-        keypad: Arc<Mutex<dyn Keypad + Send>>,
-    ) -> Self {
-
-        State {
-            memory: vec![0; MEM_SIZE],
-            pc: 0,
-            index_reg: 0,
-            stack: Vec::new(),
-            gp_registers: [0; 16],
-            rng: RngWrapper::new(),
-            display,
-            delay_timer,
-            sound_timer,
-            keypad,
+    /// Scrolls the display 4 units to the left (00FC), see [ScrollUnits].
+    pub fn scroll_left(&mut self) {
+        let columns = self.scroll_distance(4) as isize;
+        self.shift(-columns, 0);
+    }
+
+    /// Scrolls the display 4 units to the right (00FB), see [ScrollUnits].
+    pub fn scroll_right(&mut self) {
+        let columns = self.scroll_distance(4) as isize;
+        self.shift(columns, 0);
+    }
+
+    // converts a scroll distance to pixels of the current resolution, anything below 128 pixels wide is lo-res
+    fn scroll_distance(&self, n: usize) -> usize {
+        match self.scroll_units {
+            ScrollUnits::SuperChip if self.display_width < 128 => n / 2,
+            _ => n,
         }
     }
 
-    pub fn initialize(&mut self, program: &[u8], font: &[u8]) {
-        // load program into memory
-        for i in 0..program.len() {
-            self.memory[PROGRAM_START + i] = program[i];
+    // moves every pixel by (dx, dy), pixels moved out are lost and the gaps are filled with off pixels
+    fn shift(&mut self, dx: isize, dy: isize) {
+        let (width, height) = (self.display_width as isize, self.display_height as isize);
+        let mut shifted = vec![false; self.display.len()];
+        for y in 0..height {
+            for x in 0..width {
+                let (source_x, source_y) = (x - dx, y - dy);
+                if (0..width).contains(&source_x) && (0..height).contains(&source_y) {
+                    shifted[(x + y * width) as usize] = self.display[(source_x + source_y * width) as usize];
+                }
+            }
         }
+        self.display = shifted;
+    }
 
-        self.pc = PROGRAM_START;
+    /// Sets whether sprite start positions outside of the display wrap around or clip the whole sprite, see [SpriteStart].
+    pub fn set_sprite_start(&mut self, sprite_start: SpriteStart) {
+        self.sprite_start = sprite_start;
+    }
 
-        for i in 0..font.len() {
-            self.memory[FONT_START + i] = font[i];
-        }
+    ///
+    /// Returns the bounding box `(min x, min y, max x, max y)` of the pixels flipped by the last call to modify.
+    /// The bounds are inclusive. Returns `None` if the last draw did not flip any pixel.
+    ///
+    /// Front-ends can use this to highlight the region affected by a draw, e.g. for debugging overdraw.
+    pub fn last_draw_bounds(&self) -> Option<(usize, usize, usize, usize)> {
+        self.last_draw_bounds
     }
 
-    // execute the next instruction located at pc
-    pub fn execute(&mut self) {
-        // fetch, chip8 uses big endian
-        let upper = self.memory[self.pc];
-        let lower = self.memory[self.pc+1];
+    ///
+    /// Applies a batch of draws in order, see [Display::modify].
+    ///
+    /// Returns true if any of the draws turned a pixel off.
+    ///
+    /// # Arguments
+    /// * 'sprites' - tuples of (sprite, n, x, y) as passed to modify
+    pub fn modify_many(&mut self, sprites: &[(&[u8], u8, u8, u8)]) -> bool {
+        let mut result_flag = false;
+        for (sprite, n, x, y) in sprites {
+            // no short circuit, every sprite has to be drawn
+            result_flag |= self.modify(sprite, *n, *x, *y);
+        }
+        result_flag
+    }
+}
 
-        let instruction = (upper as u16) << 8 | (lower as u16);
-        // keep in mind that the pc is incremented here, important for some instructions
-        self.pc += 2;
+impl Default for DisplayBuffer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
-        //println!("{:#06x}", instruction);
-        // Decode
-        let instruction  = Instruction::decode(instruction);
+// TODO: check if the result may be reversed for the display values
+fn u8_to_bool_array(byte: u8) -> [bool; 8] {
+    let mut bool_array = [false; 8];
+    for (i, bit) in bool_array.iter_mut().enumerate() {
+        let mask = 0b10000000 >> i;
+        *bit = (byte & mask) != 0;
+    }
+    // kinda cool that this works in rust (returning array). Probably just copy
+    bool_array
+}
 
-        //println!("{:?}", instruction);
-        
+impl Display for DisplayBuffer {
+    fn modify(&mut self, sprite: &[u8], n: u8, x: u8, y: u8) -> bool {
+        // must be set to true if a pixel of the display is turned off
+        let mut result_flag = false;
+        let mut bounds: Option<(usize, usize, usize, usize)> = None;
 
-        match instruction {
-            Instruction::Cls => self.display.lock().unwrap().clear(),
-            Instruction::Rts => self.pc = self.stack.pop().unwrap(),
-            Instruction::Jump{nnn} => self.pc = nnn as usize,
-            Instruction::Call { nnn } => {
-                self.stack.push(self.pc);
-                self.pc = nnn as usize;
-            },
-            Instruction::SkipEqConst { x, nn } => if self.gp_registers[x as usize] == nn {self.pc += 2;},
-            Instruction::SkipNeqConst { x, nn } => if self.gp_registers[x as usize] != nn {self.pc += 2;},
-            Instruction::SkipEq { x, y } => if self.gp_registers[x as usize] == self.gp_registers[y as usize] {self.pc += 2},
-            Instruction::MovConst { x, nn } => self.gp_registers[x as usize] = nn,
-            Instruction::AddConst { x, nn } => self.gp_registers[x as usize] = (self.gp_registers[x as usize] as u16 + nn as u16) as u8, // properly handle overflow, as u8 should truncate
-            Instruction::Mov { x, y } => self.gp_registers[x as usize] = self.gp_registers[y as usize],
-            Instruction::Or { x, y } => self.gp_registers[x as usize] = self.gp_registers[x as usize] | self.gp_registers[y as usize] as u8,
-            Instruction::And { x, y } => self.gp_registers[x as usize] &= self.gp_registers[y as usize],
-            Instruction::Xor { x, y } => self.gp_registers[x as usize] ^= self.gp_registers[y as usize],
-            Instruction::Add { x, y } => {
-                let sum = self.gp_registers[x as usize] as u16 + self.gp_registers[y as usize] as u16;
-                if sum > 0xFF{
-                    self.gp_registers[0xF] = 1;
-                } else {
-                    self.gp_registers[0xF] = 0;
-                }
-                self.gp_registers[x as usize] = sum as u8;
-            },
-            Instruction::SubXY { x, y } => {
-                let x_val:u8 = self.gp_registers[x as usize];
-                let y_val:u8 = self.gp_registers[y as usize];
+        // nothing to draw on or a clipped start position
+        // should wrap otherwise, x = 5 should be the same as x = 68
+        let Some((actual_x, actual_y)) = self.sprite_origin(x, y) else {
+            self.last_draw_bounds = None;
+            return false;
+        };
 
+        // sprites should be clipped
+        // sprites are 8 pixels wide (each u8 of the sprite) and n pixels tall
+        // the sprite just XORs each bit with the corresponding display pixel
 
-                if x_val > y_val{
-                    self.gp_registers[0xF] = 1;
-                    self.gp_registers[x as usize] = x_val - y_val;
+        for (line, sprite_line) in sprite.iter().take(n as usize).enumerate() {
+            
+            let line_bools = u8_to_bool_array(*sprite_line);
+            //println!("\t{:?}", line_bools);
+            /*
+            line_bools.clone().map(|i| {
+                if i{
+                    print!("█");
                 } else {
-                    self.gp_registers[0xF] = 0;
-                    // TODO: check if this is the right behavior
-                    self.gp_registers[x as usize] = 0xFF - (y_val - x_val);
+                    print!(" ");
                 }
-            },
-            Instruction::RightShift { x, y: _ } => {
-                self.gp_registers[0xF] = self.gp_registers[x as usize] & 0x01;
-                self.gp_registers[x as usize] = self.gp_registers[x as usize] >> 1;
-            },
-            Instruction::SubYX { x, y } =>{
-                let x_val:u8 = self.gp_registers[x as usize];
-                let y_val:u8 = self.gp_registers[y as usize];
+            });
 
+            println!("");
+            */
 
-                if y_val > x_val{
-                    self.gp_registers[0xF] = 1;
-                    self.gp_registers[x as usize] = y_val - x_val;
-                } else {
-                    self.gp_registers[0xF] = 0;
-                    // TODO: check if this is the right behavior
-                    self.gp_registers[x as usize] = 0xFF - (x_val - y_val);
-                    
-                }
-            },
-            Instruction::LeftShift { x, y: _ } => {
-                self.gp_registers[0xF] = self.gp_registers[x as usize] & 0x80;
-                self.gp_registers[x as usize] = self.gp_registers[x as usize] << 1;
-            },
-            Instruction::SkipNeq { x, y } => {
-                if self.gp_registers[x as usize] != self.gp_registers[y as usize] {
-                    self.pc += 2;
-                }
-            },
-            Instruction::MovI { nnn } => self.index_reg = nnn,
-            Instruction::JumpIndexed { nnn } => self.pc = nnn as usize + self.gp_registers[0] as usize,
-            
-            // TODO: Rand, implement own rng, so that it is easier to compile to wasm later (rand is for some reason not wasm compatible? Better: just use wbg_rand)
-            Instruction::Rand { x, nn } => self.gp_registers[x as usize] = self.rng.generate_random_byte() & nn,
+            if actual_y + line >= self.display_height {
+                // sprite should clip so we are finished
+                break;
+            }
 
-            Instruction::Draw { x, y, n } => {
-                let res = self.display.lock().unwrap().modify(&self.memory[(self.index_reg as usize)..((self.index_reg+(n as u16)) as usize)], n, self.gp_registers[x as usize], self.gp_registers[y as usize]);
-                if res{
-                    self.gp_registers[0xF] = 1;
-                } else {
-                    self.gp_registers[0xF] = 0;
-                }
-            },
+            for (i, b) in line_bools.iter().enumerate() {
+                // drawing should clip
+                if actual_x + i < self.display_width {
+                    let index = actual_x + i + self.display_width * (line + actual_y);
+                    let old = self.display[index];
+                    // note that != is the same as a logical XOR
+                    self.display[index] = self.display[index] != *b;
 
-            Instruction::SkipKeyEq { x } => {
-                let key = self.keypad.lock().unwrap().get_pressed_key();
-                if let Some(k) = key {
-                    if k == self.gp_registers[x as usize]{
-                        self.pc += 2;
+                    // if the bit was set a pixel was flipped
+                    if *b  && old{
+                        result_flag = true;
                     }
-                }
-            },
 
-            Instruction::SkipKeyNeq { x } => {
-                let key = self.keypad.lock().unwrap().get_pressed_key();
-                if key.is_none() {
-                    self.pc += 2;
-                } else if let Some(k) = key {
-                    if k != self.gp_registers[x as usize] {
-                        self.pc += 2;
+                    if *b {
+                        let px = actual_x + i;
+                        let py = actual_y + line;
+                        bounds = Some(match bounds {
+                            None => (px, py, px, py),
+                            Some((min_x, min_y, max_x, max_y)) => {
+                                (min_x.min(px), min_y.min(py), max_x.max(px), max_y.max(py))
+                            }
+                        });
                     }
                 }
             }
-            Instruction::GetDelayTimer { x } => self.gp_registers[x as usize] = self.delay_timer.lock().unwrap().get(),
-            // just reexecutes the instruction if no key was pressed
-            Instruction::WaitKey { x } => {
-                let key = self.keypad.lock().unwrap().get_pressed_key();
-                if let Some(k) = key {
-                    self.gp_registers[x as usize] = k;
-                } else {
-                    self.pc -= 2;
-                }
-            },
-            Instruction::SetDelayTimer { x } => self.delay_timer.lock().unwrap().set(self.gp_registers[x as usize]),
-            Instruction::SetSoundTimer { x } => self.sound_timer.lock().unwrap().start(self.gp_registers[x as usize]),
-            Instruction::AddI { x } => self.index_reg = (self.index_reg + self.gp_registers[x as usize] as u16) & 0x0FFF,
-            // just consider the lower nibble of the register
-            Instruction::SetFontI { x } => self.index_reg = (FONT_START + FONT_CHARACTER_BYTES * (self.gp_registers[x as usize] & 0x0F) as usize) as u16,
-            Instruction::BCD { x } => {
-                let mut x_val = self.gp_registers[x as usize];
-                self.memory[((self.index_reg + 2) & 0x0FFF) as usize] = x_val % 10;
-                x_val /= 10;
-                self.memory[(self.index_reg + 1 & 0x0FFF) as usize] = x_val % 10;
-                x_val /= 10;
-                self.memory[self.index_reg as usize] = x_val;
-                
-            },
-            Instruction::RegDump { x } => {
-                for i in 0..=(x as usize){
-                    self.memory[(self.index_reg as usize + i ) & 0x0FFF] = self.gp_registers[i];
-                }
-            },
-            Instruction::RegLoad { x } => {
-                for i in 0..=(x as usize){
-                    self.gp_registers[i] = self.memory[(self.index_reg as usize + i ) & 0x0FFF];
-                }
-            },
+        }
+        self.last_draw_bounds = bounds;
+        result_flag
+    }
+
+    fn height(&self) -> usize {
+        self.display_height
+    }
+
+    fn width(&self) -> usize {
+        self.display_width
+    }
+
+    fn clear(&mut self) {
+        self.display.fill(false);
+    }
+
+    fn get_pixel(&self, x: usize, y: usize) -> bool {
+        if x >= self.display_width || y >= self.display_height {
+            return false;
+        }
+        self.display[x + self.display_width * y]
+    }
 
-            Instruction::Invalid =>{
-                println!("{:#04x} {:#04x}", upper, lower);
-                panic!("Not yet implemented");
-            } 
+    fn would_collide(&self, sprite: &[u8], n: u8, x: u8, y: u8) -> bool {
+        // same placement as modify: wrapped (or clipped) start, pixels clipped at the edges
+        let Some((actual_x, actual_y)) = self.sprite_origin(x, y) else {
+            return false;
+        };
+        sprite.iter().take(n as usize).enumerate().any(|(line, sprite_line)| {
+            u8_to_bool_array(*sprite_line)
+                .iter()
+                .enumerate()
+                .any(|(i, b)| *b && self.get_pixel(actual_x + i, actual_y + line))
+        })
+    }
+
+    fn sprite_origin(&self, x: u8, y: u8) -> Option<(usize, usize)> {
+        // nothing to draw on, also avoids the modulo by zero below
+        if self.display_width == 0 || self.display_height == 0 {
+            return None;
         }
+        if self.sprite_start == SpriteStart::Clip && (x as usize >= self.display_width || y as usize >= self.display_height) {
+            return None;
+        }
+        Some((x as usize % self.display_width, y as usize % self.display_height))
+    }
+
+    fn set_resolution(&mut self, width: usize, height: usize) {
+        self.display_width = width;
+        self.display_height = height;
+        self.display = vec![false; width * height];
+        self.presented = vec![false; width * height];
+        self.last_draw_bounds = None;
     }
 }
+// ----------------------------------------------------------------
 
+// Proper timer, beeper and keypad implementations
+// ----------------------------------------------------------------
 
+/// A delay timer that counts down when ticked, see [State::tick_timers].
+#[derive(Debug, Default)]
+pub struct CountdownTimer {
+    value: u8,
+}
 
-// Mnemonics are (mostly) taken from: http://www.emulator101.com/chip-8-instruction-set.html
-// also https://en.wikipedia.org/wiki/CHIP-8
-// X: second nibble of instruction. Used to look up one of the 16 registers
-// Y: third nibble of instruction. Used to look up one of the 16 registers
-// N: The *fourth* nibble
-// NN: second byte, immediate 8-bit number
-// NNN: second, third and fourth nibble, immediate 12-bit address
-#[derive(Debug)]
-pub enum Instruction {
-    // 0NNN, Instruction 0NNN calls a machine code routine (RCA 1802 for COSMAC VIP), I won't implement this instruction
-    // use Invalid for this Instruction
-    Invalid,
-    // 00E0, clear screen
-    Cls,
-    // 00EE, return from subroutine
-    Rts,
-    // 1NNN, absolute jump to NNN
-    Jump { nnn: u16 },
-    // 2NNN, jump to subroutine at NNN (push address to stack, change pc)
-    Call { nnn: u16 },
-    // 3XNN, skip next instruction if Vx equals NN
-    SkipEqConst { x: u8, nn: u8 },
-    // 4XNN, skip next instruction if Vx does not equal NN
-    SkipNeqConst { x: u8, nn: u8 },
-    // 5XY0, skips the next instruction if VX equals VY
-    SkipEq { x: u8, y: u8 },
-    // 6XNN, Sets VX to NN.
-    MovConst { x: u8, nn: u8 },
-    // 7XNN, Adds NN to VX (carry flag is not changed)
-    AddConst { x: u8, nn: u8 },
-    // 8XY0, Sets VX to the value of VY.
-    Mov { x: u8, y: u8 },
-    // 8XY1, Sets VX to VX or VY. (bitwise OR operation)
-    Or { x: u8, y: u8 },
-    // 8XY2, Sets VX to VX and VY. (bitwise AND operation)
-    And { x: u8, y: u8 },
-    // 8XY3, Sets VX to VX xor VY
-    Xor { x: u8, y: u8 },
-    // 8XY4, Adds VY to VX. VF is set to 1 when there's a carry, and to 0 when there is not.
-    Add { x: u8, y: u8 },
-    // 8XY5, VY is subtracted from VX. VF is set to 0 when there's a borrow, and 1 when there is not.
-    SubXY { x: u8, y: u8 },
-    // 8XY6, Stores the least significant bit of VX in VF and then shifts VX to the right by 1 (ambiguous see chip8 guide)
-    RightShift { x: u8, y: u8 },
-    // 8XY7, Sets VX to VY minus VX. VF is set to 0 when there's a borrow, and 1 when there is not.
-    SubYX { x: u8, y: u8 },
-    // 8XYE, Stores the most significant bit of VX in VF and then shifts VX to the left by 1
-    LeftShift { x: u8, y: u8 },
-    // 9XY0, Skips the next instruction if VX does not equal VY
-    SkipNeq { x: u8, y: u8 },
-    // ANNN, Sets I to the address NNN
-    MovI { nnn: u16 },
-    // BNNN, indexed jump, jump to NNN + V0, Ambiguous 
-    JumpIndexed { nnn: u16 },
-    // CXNN, Sets VX to the result of a bitwise and operation on a random number (Typically: 0 to 255) and NN
-    Rand { x: u8, nn: u8 },
-    // DXYN, Draws a sprite at coordinate (VX, VY) that has a width of 8 pixels and a height of N pixels. Each row of 8 pixels is read as bit-coded starting from memory location I; I value does not change after the execution of this instruction. VF will be set if a screen pixel was changed
-    Draw { x: u8, y: u8, n: u8 },
-    // EX9E, Skips the next instruction if the key stored in VX is pressed
-    SkipKeyEq { x: u8 },
-    // EXA1, Skips the next instruction if the key stored in VX is not pressed
-    SkipKeyNeq { x: u8 },
-    // FX07, Sets VX to the value of the delay timer
-    GetDelayTimer { x: u8 },
-    // FX0A, A key press is awaited, and then stored in VX
-    WaitKey { x: u8 },
-    // FX15, set delay timer to VX
-    SetDelayTimer { x: u8 },
-    // FX18, Sets the sound timer to VX.
+impl CountdownTimer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Timer for CountdownTimer {
+    fn set(&mut self, val: u8) {
+        self.value = val;
+    }
+
+    fn get(&self) -> u8 {
+        self.value
+    }
+}
+
+/// A beeper that only keeps the counter, the host plays a sound while [CountdownBeeper::is_beeping] is true.
+#[derive(Debug, Default)]
+pub struct CountdownBeeper {
+    remaining: u8,
+}
+
+impl CountdownBeeper {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn is_beeping(&self) -> bool {
+        self.remaining > 0
+    }
+}
+
+impl Beeper for CountdownBeeper {
+    fn start(&mut self, time: u8) {
+        self.remaining = time;
+    }
+
+    fn remaining(&self) -> u8 {
+        self.remaining
+    }
+
+    fn tick(&mut self) {
+        self.remaining = self.remaining.saturating_sub(1);
+    }
+}
+
+/// Keeps track of the pressed keys. The host calls press and release from its input handling.
+#[derive(Debug, Default)]
+pub struct KeypadState {
+    pressed: [bool; 16],
+}
+
+impl KeypadState {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Marks key (0x0 .. 0xF) as pressed, other values are ignored.
+    pub fn press(&mut self, key: u8) {
+        if let Some(pressed) = self.pressed.get_mut(key as usize) {
+            *pressed = true;
+        }
+    }
+
+    /// Marks key (0x0 .. 0xF) as released, other values are ignored.
+    pub fn release(&mut self, key: u8) {
+        if let Some(pressed) = self.pressed.get_mut(key as usize) {
+            *pressed = false;
+        }
+    }
+
+    pub fn is_pressed(&self, key: u8) -> bool {
+        self.pressed.get(key as usize).copied().unwrap_or(false)
+    }
+}
+
+impl Keypad for KeypadState {
+    // if several keys are pressed the lowest one wins
+    fn get_pressed_key(&self) -> Option<u8> {
+        self.pressed.iter().position(|p| *p).map(|key| key as u8)
+    }
+}
+
+/// Handles to the peripherals of a [State] created by [State::from_buffers], so that the host can still render the
+/// display, feed the keypad and play the beeper.
+#[cfg(feature = "std")]
+#[derive(Clone)]
+pub struct SharedPeripherals {
+    pub display: Arc<Mutex<DisplayBuffer>>,
+    pub delay_timer: Arc<Mutex<CountdownTimer>>,
+    pub sound_timer: Arc<Mutex<CountdownBeeper>>,
+    pub keypad: Arc<Mutex<KeypadState>>,
+}
+// ----------------------------------------------------------------
+
+#[cfg(feature = "std")]
+pub type SharedDisplay = Arc<Mutex<dyn Display + Send>>;
+#[cfg(feature = "std")]
+pub type SharedTimer = Arc<Mutex<dyn Timer + Send>>;
+#[cfg(feature = "std")]
+pub type SharedBeeper = Arc<Mutex<dyn Beeper + Send>>;
+#[cfg(feature = "std")]
+pub type SharedKeypad = Arc<Mutex<dyn Keypad + Send>>;
+
+/// The interpreter with peripherals shared as `Arc<Mutex<_>>`, so that the host can render, feed the keypad and play
+/// the beeper from other threads. Every use of a peripheral locks it, a [Core] that owns its peripherals does not.
+#[cfg(feature = "std")]
+pub type State = Core<SharedDisplay, SharedTimer, SharedBeeper, SharedKeypad>;
+
+// shared peripherals lock on every call, which lets a Core use them like owned ones
+#[cfg(feature = "std")]
+impl<P: Display + ?Sized> Display for Arc<Mutex<P>> {
+    fn modify(&mut self, sprite: &[u8], n: u8, x: u8, y: u8) -> bool {
+        self.lock().unwrap().modify(sprite, n, x, y)
+    }
+
+    fn width(&self) -> usize {
+        self.lock().unwrap().width()
+    }
+
+    fn height(&self) -> usize {
+        self.lock().unwrap().height()
+    }
+
+    fn clear(&mut self) {
+        self.lock().unwrap().clear()
+    }
+
+    fn dimensions(&self) -> (usize, usize) {
+        self.lock().unwrap().dimensions()
+    }
+
+    fn get_pixel(&self, x: usize, y: usize) -> bool {
+        self.lock().unwrap().get_pixel(x, y)
+    }
+
+    fn would_collide(&self, sprite: &[u8], n: u8, x: u8, y: u8) -> bool {
+        self.lock().unwrap().would_collide(sprite, n, x, y)
+    }
+
+    fn set_resolution(&mut self, width: usize, height: usize) {
+        self.lock().unwrap().set_resolution(width, height)
+    }
+
+    fn sprite_origin(&self, x: u8, y: u8) -> Option<(usize, usize)> {
+        self.lock().unwrap().sprite_origin(x, y)
+    }
+}
+
+#[cfg(feature = "std")]
+impl<P: Timer + ?Sized> Timer for Arc<Mutex<P>> {
+    fn set(&mut self, val: u8) {
+        self.lock().unwrap().set(val)
+    }
+
+    fn get(&self) -> u8 {
+        self.lock().unwrap().get()
+    }
+}
+
+#[cfg(feature = "std")]
+impl<P: Beeper + ?Sized> Beeper for Arc<Mutex<P>> {
+    fn start(&mut self, time: u8) {
+        self.lock().unwrap().start(time)
+    }
+
+    fn remaining(&self) -> u8 {
+        self.lock().unwrap().remaining()
+    }
+
+    fn tick(&mut self) {
+        self.lock().unwrap().tick()
+    }
+}
+
+#[cfg(feature = "std")]
+impl<P: Keypad + ?Sized> Keypad for Arc<Mutex<P>> {
+    fn get_pressed_key(&self) -> Option<u8> {
+        self.lock().unwrap().get_pressed_key()
+    }
+}
+
+// Rand uses the thread rng with std. Without std there is no source of entropy, Rand is seeded with a constant
+#[cfg(feature = "std")]
+fn default_rng() -> Box<dyn RandomSource> {
+    Box::new(rand::thread_rng())
+}
+
+#[cfg(not(feature = "std"))]
+fn default_rng() -> Box<dyn RandomSource> {
+    Box::new(SeededRng::new(0))
+}
+
+#[cfg(feature = "std")]
+impl State {
+    ///
+    /// Creates a [Core] that owns a [CountdownTimer] and a [CountdownBeeper] by value, for hosts that share the display
+    /// and the keypad but do not need to access the timers themselves. The timers are not locked, they count down with
+    /// [Core::tick_timers], use [Core::should_beep] to play the sound.
+    pub fn with_owned_timers(
+        display: SharedDisplay,
+        keypad: SharedKeypad,
+    ) -> Core<SharedDisplay, CountdownTimer, CountdownBeeper, SharedKeypad> {
+        Core::new(display, CountdownTimer::new(), CountdownBeeper::new(), keypad)
+    }
+
+    ///
+    /// Wraps the built-in peripherals in `Arc<Mutex<_>>` and creates a [State] with them.
+    ///
+    /// Returns the state and clones of the handles, so that the host can still access the peripherals.
+    pub fn from_buffers(
+        display: DisplayBuffer,
+        delay_timer: CountdownTimer,
+        sound_timer: CountdownBeeper,
+        keypad: KeypadState,
+    ) -> (State, SharedPeripherals) {
+        let peripherals = SharedPeripherals {
+            display: Arc::new(Mutex::new(display)),
+            delay_timer: Arc::new(Mutex::new(delay_timer)),
+            sound_timer: Arc::new(Mutex::new(sound_timer)),
+            keypad: Arc::new(Mutex::new(keypad)),
+        };
+        let state = State::new(
+            peripherals.display.clone(),
+            peripherals.delay_timer.clone(),
+            peripherals.sound_timer.clone(),
+            peripherals.keypad.clone(),
+        );
+        (state, peripherals)
+    }
+
+    ///
+    /// Creates a [State] with the null peripherals ([NullDisplay], [NullTimer], [NullBeeper] and [NullKeypad]).
+    ///
+    /// This is the minimal setup for testing instruction logic that does not need any IO.
+    pub fn headless() -> Self {
+        State::new(
+            Arc::new(Mutex::new(NullDisplay)),
+            Arc::new(Mutex::new(NullTimer)),
+            Arc::new(Mutex::new(NullBeeper)),
+            Arc::new(Mutex::new(NullKeypad)),
+        )
+    }
+
+    ///
+    /// The shortest way to run a ROM: creates the built-in peripherals, a [State] with quirks and loads the ROM at path
+    /// with the default font.
+    ///
+    /// Returns the state and the handles to the peripherals, see [State::from_buffers]. Fails if the file can't be
+    /// read or does not fit into memory.
+    ///
+    /// # Arguments
+    /// * 'path' - the ROM file
+    /// * 'quirks' - the interpreter behaviors the ROM expects
+    pub fn from_rom_file(path: impl AsRef<std::path::Path>, quirks: Quirks) -> std::io::Result<(State, SharedPeripherals)> {
+        let rom = std::fs::read(path)?;
+        if rom.len() > MEM_SIZE - PROGRAM_START {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("ROM has {} bytes, at most {} fit into memory", rom.len(), MEM_SIZE - PROGRAM_START),
+            ));
+        }
+        let (mut state, peripherals) = State::from_buffers(
+            DisplayBuffer::new(),
+            CountdownTimer::new(),
+            CountdownBeeper::new(),
+            KeypadState::new(),
+        );
+        state.set_quirks(quirks);
+        state.initialize(&rom, &DEFAULT_FONT);
+        Ok((state, peripherals))
+    }
+}
+
+impl<D: Display, T: Timer, B: Beeper, K: Keypad> Core<D, T, B, K> {
+    ///
+    /// Creates a permissive interpreter with the given peripherals, see [Core::new_strict] for the checked variant.
+    /// Call [Core::initialize] to load a program.
+    pub fn new(display: D, delay_timer: T, sound_timer: B, keypad: K) -> Self {
+        Core {
+            memory: Box::new(LinearMemory::default()),
+            pc: 0,
+            index_reg: 0,
+            stack: Vec::new(),
+            gp_registers: [0; 16],
+            quirks: Quirks::default(),
+            rng: default_rng(),
+            display,
+            delay_timer,
+            sound_timer,
+            keypad,
+            require_aligned_pc: false,
+            strict_memory: false,
+            stack_limit: None,
+            stack_policy: StackPolicy::default(),
+            enabled_instructions: InstructionSet::ALL,
+            keypad_latch: false,
+            latched_key: None,
+            input_recording: None,
+            replay_inputs: Vec::new(),
+            replay_frame: 0,
+            wait_spins: 0,
+            max_wait_spins: None,
+            memory_fill: 0,
+            font_address: FONT_START,
+            cpu_phase: 0,
+            timer_phase: 0,
+            step_timer_phase: 0,
+            invalid_opcode_errors: false,
+            extension_handler: None,
+            sound_hook: None,
+            draw_interference_hook: None,
+            program_write_hook: None,
+            program_len: 0,
+            fetched: (PROGRAM_START, 0),
+            min_sound_timer: 1,
+            machine_cycles: 0,
+            clear_display_on_init: true,
+            boot_timers: (0, 0),
+            frame_collision: false,
+            needs_present: false,
+            hires: false,
+            vf_watch: None,
+            written_registers: 0,
+            uninitialized_read_hook: None,
+            opcode_log: None,
+        }
+    }
+
+    ///
+    /// Like [State::new] but with all safety checks enabled, intended for developing and debugging ROMs:
+    /// * memory accesses that would leave the memory return [ExecutionError::MemoryOutOfBounds] instead of wrapping around
+    /// * BCD below 0x200 returns [ExecutionError::ReservedMemoryWrite] instead of overwriting the font
+    /// * the stack is limited to 16 entries, see [ExecutionError::StackOverflow] and [ExecutionError::StackUnderflow]
+    /// * the pc must be aligned, see [State::set_require_aligned_pc]
+    /// * opcodes that do not decode return [ExecutionError::InvalidOpcode] instead of being skipped
+    ///
+    /// The checks cost a few comparisons per instruction and turn ROM bugs into errors. [State::new] stays permissive:
+    /// it wraps memory accesses and skips invalid opcodes, which is what most ROMs expect.
+    pub fn new_strict(display: D, delay_timer: T, sound_timer: B, keypad: K) -> Self {
+        let mut state = Self::new(display, delay_timer, sound_timer, keypad);
+        state.require_aligned_pc = true;
+        state.strict_memory = true;
+        state.stack_limit = Some(STACK_SIZE);
+        state.stack_policy = StackPolicy::Error;
+        state.invalid_opcode_errors = true;
+        state
+    }
+
+    ///
+    /// Presets the delay timer and the beeper right after construction and on every [State::initialize]. A handful of ROMs assume the timers are already running on boot.
+    ///
+    /// # Arguments
+    /// * 'delay' - initial value of the delay timer, passed to [Timer::set]
+    /// * 'sound' - initial value of the sound timer, passed to [Beeper::start]
+    pub fn with_timers(mut self, delay: u8, sound: u8) -> Self {
+        self.boot_timers = (delay, sound);
+        self.delay_timer.set(delay);
+        self.sound_timer.start(sound);
+        self
+    }
+
+    ///
+    /// Fills the memory outside of program and font with fill instead of zeros, now and on every [State::initialize].
+    /// A fill like 0xFF surfaces ROMs that read memory they never wrote, which is useful for fuzzing.
+    ///
+    /// # Arguments
+    /// * 'fill' - value of every byte that is not part of program or font
+    pub fn with_memory_fill(mut self, fill: u8) -> Self {
+        self.memory_fill = fill;
+        self.fill_memory();
+        self
+    }
+
+    ///
+    /// Replaces the thread rng with a [SeededRng], so that Rand produces the same numbers on every run.
+    /// Needed for reproducible tests and [State::trace_hash].
+    pub fn with_rng_seed(mut self, seed: u64) -> Self {
+        self.rng = Box::new(SeededRng::new(seed));
+        self
+    }
+
+    ///
+    /// Replaces the source of Rand, also while running, e.g. to feed a canned sequence from a breakpoint on.
+    pub fn set_rng(&mut self, source: Box<dyn RandomSource>) {
+        self.rng = source;
+    }
+
+    ///
+    /// Sets the interpreter behaviors that differ between chip8 implementations, see [Quirks].
+    pub fn set_quirks(&mut self, quirks: Quirks) {
+        self.quirks = quirks;
+    }
+
+    ///
+    /// Returns the active quirks, e.g. to show them in a settings UI.
+    pub fn quirks(&self) -> Quirks {
+        self.quirks
+    }
+
+    ///
+    /// Resizes the memory, e.g. to the 64KiB of XO-CHIP. The content is kept (and cut off when shrinking), new bytes
+    /// are set to the fill of [State::with_memory_fill].
+    /// Addresses computed by instructions, like the index register after AddI, wrap around at the memory size.
+    ///
+    /// A backend set with [State::set_memory] is kept and resized with [Memory::resize].
+    pub fn set_memory_size(&mut self, size: MemorySize) {
+        self.memory.resize(size.bytes(), self.memory_fill);
+        self.index_reg = (self.index_reg as usize & self.address_mask()) as u16;
+    }
+
+    ///
+    /// Replaces the memory backend, e.g. with one that traces accesses. The content of the old memory is dropped,
+    /// call [State::initialize] afterwards to load a program.
+    ///
+    /// # Arguments
+    /// * 'memory' - the new memory, its size must be a power of two
+    pub fn set_memory(&mut self, memory: Box<dyn Memory>) {
+        debug_assert!(memory.size().is_power_of_two());
+        self.memory = memory;
+        self.index_reg = (self.index_reg as usize & self.address_mask()) as u16;
+    }
+
+    ///
+    /// Sets what happens on a return with an empty stack or a call beyond the stack limit, see [StackPolicy].
+    /// [State::new] saturates so broken ROMs keep running, [State::new_strict] returns errors.
+    pub fn set_stack_policy(&mut self, policy: StackPolicy) {
+        self.stack_policy = policy;
+    }
+
+    ///
+    /// Restricts the instructions that may be executed, e.g. for sandboxing or for a classroom exercise without display
+    /// instructions. Disabled instructions return [ExecutionError::DisabledInstruction]. All are enabled by default.
+    pub fn set_enabled_instructions(&mut self, enabled: InstructionSet) {
+        self.enabled_instructions = enabled;
+    }
+
+    ///
+    /// After max WaitKey executions in a row without a pressed key, [State::execute] returns [StepOutcome::StalledOnKey]
+    /// (and keeps doing so until a key is pressed), so a host executing in a loop knows to stop and pump input.
+    /// None, the default, always returns [StepOutcome::Executed].
+    pub fn set_max_wait_spins(&mut self, max: Option<usize>) {
+        self.max_wait_spins = max;
+    }
+
+    ///
+    /// If set, [State::execute] returns [ExecutionError::MisalignedPc] instead of fetching from an odd address.
+    /// Otherwise the instruction is fetched anyway, which usually mis-decodes data or the second half of an instruction.
+    pub fn set_require_aligned_pc(&mut self, require: bool) {
+        self.require_aligned_pc = require;
+    }
+
+    ///
+    /// Returns true if both states have the same memory, registers, pc, index register and stack.
+    /// The peripherals are not compared.
+    pub fn cpu_state_eq(&self, other: &Self) -> bool {
+        self.diff(other).is_none()
+    }
+
+    ///
+    /// Describes the first difference between the cpu state of self and other, see [State::cpu_state_eq].
+    ///
+    /// Returns `None` if there is no difference.
+    pub fn diff(&self, other: &Self) -> Option<String> {
+        if self.pc != other.pc {
+            return Some(format!("pc: {:#05x} != {:#05x}", self.pc, other.pc));
+        }
+        if self.index_reg != other.index_reg {
+            return Some(format!("I: {:#05x} != {:#05x}", self.index_reg, other.index_reg));
+        }
+        for (i, (a, b)) in self.gp_registers.iter().zip(other.gp_registers.iter()).enumerate() {
+            if a != b {
+                return Some(format!("V{:X}: {:#04x} != {:#04x}", i, a, b));
+            }
+        }
+        if self.stack != other.stack {
+            return Some(format!("stack: {:x?} != {:x?}", self.stack, other.stack));
+        }
+        if self.memory.size() != other.memory.size() {
+            return Some(format!("memory size: {} != {}", self.memory.size(), other.memory.size()));
+        }
+        for addr in 0..self.memory.size() {
+            let (a, b) = (self.memory.read(addr), other.memory.read(addr));
+            if a != b {
+                return Some(format!("memory[{:#05x}]: {:#04x} != {:#04x}", addr, a, b));
+            }
+        }
+        None
+    }
+
+    ///
+    /// Installs a hook that is called with true when a SetSoundTimer instruction starts the beeper with a nonzero value
+    /// and with false when the beeper reaches zero during [State::tick_timers].
+    ///
+    /// Audio backends can use this to start and stop an oscillator instead of polling the beeper.
+    pub fn set_sound_hook(&mut self, hook: Box<dyn FnMut(bool)>) {
+        self.sound_hook = Some(hook);
+    }
+
+    ///
+    /// Sets the smallest sound timer value that starts the beeper. SetSoundTimer with a smaller value is treated as 0,
+    /// like emulators that don't beep for a single frame. The default 1 beeps on any nonzero value.
+    pub fn set_min_sound_timer(&mut self, min: u8) {
+        self.min_sound_timer = min;
+    }
+
+    ///
+    /// Installs a hook that is called with the address and the value whenever BCD or RegDump writes into the program
+    /// loaded by [State::initialize], to find self-modifying code while debugging. The write still happens.
+    pub fn set_program_write_hook(&mut self, hook: Box<dyn FnMut(usize, u8)>) {
+        self.program_write_hook = Some(hook);
+    }
+
+    ///
+    /// Installs a handler for opcodes that do not decode, to prototype new instructions. It is called with the state and
+    /// the opcode, the pc already points behind the opcode. If it returns true the opcode counts as executed, otherwise
+    /// it is skipped or returns [ExecutionError::InvalidOpcode] as usual.
+    pub fn set_extension_handler(&mut self, handler: ExtensionHandler<D, T, B, K>) {
+        self.extension_handler = Some(handler);
+    }
+
+    ///
+    /// Installs a hook that is called once at every DRW, before the sprite is drawn. On the COSMAC VIP the video
+    /// output was interrupted while the interpreter drew, front-ends aiming for authenticity can flash the screen here.
+    pub fn set_draw_interference_hook(&mut self, hook: Box<dyn FnMut()>) {
+        self.draw_interference_hook = Some(hook);
+    }
+
+    ///
+    /// Installs a hook that is called whenever an instruction writes VF, either as flag or as destination register.
+    /// The hook gets the new value of VF and the mnemonic of the instruction, see [Instruction::mnemonic].
+    ///
+    /// Many quirks change how VF is written, so this helps to track down differences to e.g. the quirks test ROM.
+    pub fn set_vf_watch(&mut self, hook: Box<dyn FnMut(u8, &'static str)>) {
+        self.vf_watch = Some(hook);
+    }
+
+    ///
+    /// Installs a hook that is called when an instruction reads a general purpose register that was not written since
+    /// the last [State::initialize]. The hook gets the register and the address of the instruction.
+    ///
+    /// Relying on registers being zero on boot is a common bug in beginner ROMs.
+    pub fn set_uninitialized_read_hook(&mut self, hook: Box<dyn FnMut(u8, usize)>) {
+        self.uninitialized_read_hook = Some(hook);
+    }
+
+    ///
+    /// Enables or disables recording of every executed instruction as (address, opcode), see [State::opcode_log].
+    /// Disabling drops the recorded log.
+    pub fn set_opcode_logging(&mut self, enabled: bool) {
+        if !enabled {
+            self.opcode_log = None;
+        } else if self.opcode_log.is_none() {
+            self.opcode_log = Some(Vec::new());
+        }
+    }
+
+    ///
+    /// Returns the (address, opcode) pairs executed since logging was enabled or the log was drained, in order.
+    ///
+    /// Unlike a trace hook the log is a plain buffer, so two runs (or two emulators) can be diffed afterwards.
+    pub fn opcode_log(&self) -> &[(usize, u16)] {
+        self.opcode_log.as_deref().unwrap_or(&[])
+    }
+
+    ///
+    /// Takes the recorded log and starts a new one, so that long runs do not grow it without limit.
+    pub fn drain_opcode_log(&mut self) -> Vec<(usize, u16)> {
+        match &mut self.opcode_log {
+            Some(log) => core::mem::take(log),
+            None => Vec::new(),
+        }
+    }
+
+    ///
+    /// Decrements the delay timer and the beeper by one, unless they are already zero.
+    ///
+    /// Hosts whose timers do not count down on their own must call this 60 times per second.
+    pub fn tick_timers(&mut self) {
+        {
+            let value = self.delay_timer.get();
+            if value > 0 {
+                self.delay_timer.set(value - 1);
+            }
+        }
+
+        let stopped = {
+            let beeper = &mut self.sound_timer;
+            if beeper.remaining() > 0 {
+                beeper.tick();
+                beeper.remaining() == 0
+            } else {
+                false
+            }
+        };
+        if stopped {
+            if let Some(hook) = &mut self.sound_hook {
+                hook(false);
+            }
+        }
+
+        // a timer tick is the frame boundary
+        if self.replay_frame < self.replay_inputs.len() {
+            self.replay_frame += 1;
+        }
+        let key = self.read_keypad();
+        if let Some(recording) = &mut self.input_recording {
+            recording.push(key.map_or(0, |key| 1 << key));
+        }
+        if self.keypad_latch {
+            self.latched_key = key;
+        }
+    }
+
+    ///
+    /// Returns the number of timer ticks until the sound timer runs out, so the host can schedule a tone of exactly
+    /// that length.
+    pub fn sound_frames_remaining(&self) -> u8 {
+        self.sound_timer.remaining()
+    }
+
+    ///
+    /// Returns true while the sound timer is running, i.e. the host should play the tone.
+    pub fn should_beep(&self) -> bool {
+        self.sound_frames_remaining() > 0
+    }
+
+    ///
+    /// If enabled, the keypad is read once per frame instead of at every key instruction: the pressed key is latched
+    /// now and at every [State::tick_timers], and all instructions until the next tick see that key. Keeps SkipKeyEq
+    /// followed by WaitKey consistent within a frame even if the host updates the keypad from another thread.
+    pub fn set_keypad_latch(&mut self, enabled: bool) {
+        self.keypad_latch = enabled;
+        self.latched_key = self.read_keypad();
+    }
+
+    ///
+    /// Starts recording the keypad once per frame for [State::replay]: the pressed key now and after every
+    /// [State::tick_timers] as a mask with bit k set for key k. A running recording is discarded.
+    ///
+    /// Together with [State::with_rng_seed] the recording reproduces a run exactly if the keypad is latched
+    /// ([State::set_keypad_latch]), otherwise key changes within a frame are lost.
+    pub fn start_recording(&mut self) {
+        let key = self.read_keypad();
+        self.input_recording = Some(vec![key.map_or(0, |key| 1 << key)]);
+    }
+
+    ///
+    /// Stops the recording and returns the key mask of every frame, empty if no recording was running.
+    pub fn stop_recording(&mut self) -> Vec<u16> {
+        self.input_recording.take().unwrap_or_default()
+    }
+
+    ///
+    /// Reads the keypad from inputs instead of the host: frame k, which starts with the k-th [State::tick_timers]
+    /// from now, sees inputs\[k]. If a mask has several keys, the lowest one is pressed. After the last frame the
+    /// keypad is used again.
+    ///
+    /// # Arguments
+    /// * 'inputs' - key masks as returned by [State::stop_recording]
+    pub fn replay(&mut self, inputs: &[u16]) {
+        self.replay_inputs = inputs.to_vec();
+        self.replay_frame = 0;
+        self.latched_key = self.read_keypad();
+    }
+
+    ///
+    /// Formats V0..VF, the pc, I, the stack depth and the timers as a multi-line table, e.g. for debug output.
+    pub fn dump_registers(&self) -> String {
+        let mut dump = String::new();
+        for (row, registers) in self.gp_registers.chunks(4).enumerate() {
+            let line: Vec<String> = registers
+                .iter()
+                .enumerate()
+                .map(|(i, value)| format!("V{:X}={:02x}", row * 4 + i, value))
+                .collect();
+            dump.push_str(&line.join(" "));
+            dump.push('\n');
+        }
+        dump.push_str(&format!("PC={:#05x} I={:#05x} SP={}\n", self.pc, self.index_reg, self.stack.len()));
+        dump.push_str(&format!(
+            "DT={:02x} ST={:02x}\n",
+            self.delay_timer.get(),
+            self.sound_timer.remaining()
+        ));
+        dump
+    }
+
+    ///
+    /// Formats the pc and the return addresses on the stack as a backtrace, innermost first, e.g. for a debugger's
+    /// `bt` command: `#0 0x2a4  #1 0x210  #2 0x202` where #0 is the pc.
+    pub fn backtrace(&self) -> String {
+        let frames: Vec<String> = core::iter::once(&self.pc)
+            .chain(self.stack.iter().rev())
+            .enumerate()
+            .map(|(i, address)| format!("#{} {:#05x}", i, address))
+            .collect();
+        frames.join("  ")
+    }
+
+    ///
+    /// Sets whether [State::initialize] clears the display. Enabled by default, disable it to keep the
+    /// last picture of the previous ROM on screen.
+    pub fn set_clear_display_on_init(&mut self, clear: bool) {
+        self.clear_display_on_init = clear;
+    }
+
+    ///
+    /// Resets the machine and loads program and font. Memory, registers, I, the stack, a pending key wait and (unless
+    /// disabled with [State::set_clear_display_on_init]) the display are cleared and the timers are set to the values of
+    /// [State::with_timers] (0 by default), so a State can be reused for another ROM.
+    pub fn initialize(&mut self, program: &[u8], font: &[u8]) {
+        self.initialize_with_font_at(program, font, FONT_START);
+    }
+
+    ///
+    /// Like [State::initialize], but loads the font to font_address instead of 0x50. SetFontI points into the font there.
+    /// The font is written after the program, so for ROMs that bring their own font font can be empty and font_address
+    /// point into the program.
+    ///
+    /// # Arguments
+    /// * 'program' - the ROM, loaded to 0x200
+    /// * 'font' - 16 glyphs of 5 bytes
+    /// * 'font_address' - where the font is loaded to
+    pub fn initialize_with_font_at(&mut self, program: &[u8], font: &[u8], font_address: usize) {
+        self.fill_memory();
+        self.gp_registers = [0; 16];
+        self.index_reg = 0;
+        self.stack.clear();
+        // SUPER-CHIP ROMs expect to start in lo-res
+        if self.hires {
+            self.set_lores();
+        }
+        if self.clear_display_on_init {
+            self.display.clear();
+            self.needs_present = true;
+        }
+
+        // load program into memory
+        self.write_bytes(PROGRAM_START, program);
+        self.program_len = program.len();
+
+        self.pc = PROGRAM_START;
+
+        self.write_bytes(font_address, font);
+        self.font_address = font_address;
+
+        let (delay, sound) = self.boot_timers;
+        self.delay_timer.set(delay);
+        self.sound_timer.start(sound);
+        self.wait_spins = 0;
+        self.latched_key = None;
+        self.frame_collision = false;
+        self.written_registers = 0;
+        self.cpu_phase = 0;
+        self.timer_phase = 0;
+        self.step_timer_phase = 0;
+    }
+
+    fn fill_memory(&mut self) {
+        for addr in 0..self.memory.size() {
+            self.memory.write(addr, self.memory_fill);
+        }
+    }
+
+    // panics like a slice copy if bytes do not fit into memory
+    fn write_bytes(&mut self, start: usize, bytes: &[u8]) {
+        assert!(start + bytes.len() <= self.memory.size(), "{} bytes do not fit into memory at {:#05x}", bytes.len(), start);
+        for (i, byte) in bytes.iter().enumerate() {
+            self.memory.write(start + i, *byte);
+        }
+    }
+
+    // memory write of an instruction, reported to the program write hook
+    fn write_memory(&mut self, addr: usize, value: u8) {
+        self.memory.write(addr, value);
+        if (PROGRAM_START..PROGRAM_START + self.program_len).contains(&addr) {
+            if let Some(hook) = &mut self.program_write_hook {
+                hook(addr, value);
+            }
+        }
+    }
+
+    // the memory size is a power of two, so this can be used to wrap addresses
+    fn address_mask(&self) -> usize {
+        self.memory.size() - 1
+    }
+
+    // checks that len bytes starting at addr lie in memory, only enforced with strict memory
+    fn check_memory(&self, addr: usize, len: usize) -> Result<(), ExecutionError> {
+        if self.strict_memory && addr + len > self.memory.size() {
+            return Err(ExecutionError::MemoryOutOfBounds(self.memory.size().max(addr)));
+        }
+        Ok(())
+    }
+
+    // skips the instruction at pc, the 4 byte XO-CHIP F000 NNNN as a whole if the quirk is set
+    fn skip_next(&mut self) {
+        let mask = self.address_mask();
+        let long = self.quirks.skip_long_instructions
+            && self.memory.read(self.pc & mask) == 0xF0
+            && self.memory.read((self.pc + 1) & mask) == 0x00;
+        self.pc += if long { 4 } else { 2 };
+    }
+
+    // the flag is written last so it survives X = F, unless the legacy order is set
+    fn write_result_and_flag(&mut self, x: u8, result: u8, flag: u8) {
+        if self.quirks.vf_order_legacy {
+            self.gp_registers[0xF] = flag;
+            self.gp_registers[x as usize] = result;
+        } else {
+            self.gp_registers[x as usize] = result;
+            self.gp_registers[0xF] = flag;
+        }
+    }
+
+    fn increment_index_after_load_store(&mut self, x: u8) {
+        let increment = match self.quirks.load_store_increment {
+            IndexIncrement::None => 0,
+            IndexIncrement::X => x as usize,
+            IndexIncrement::XPlus1 => x as usize + 1,
+        };
+        self.index_reg = ((self.index_reg as usize + increment) & self.address_mask()) as u16;
+    }
+
+    // the key the instructions see, the latched one if the latch is enabled
+    fn pressed_key(&self) -> Option<u8> {
+        if self.keypad_latch {
+            self.latched_key
+        } else {
+            self.read_keypad()
+        }
+    }
+
+    // keys are 0x0 .. 0xF, anything above would corrupt registers used by e.g. SetFontI
+    fn read_keypad(&self) -> Option<u8> {
+        if let Some(mask) = self.replay_inputs.get(self.replay_frame) {
+            return (*mask != 0).then(|| mask.trailing_zeros() as u8);
+        }
+        self.keypad.get_pressed_key().map(|key| key & 0x0F)
+    }
+
+    // resets the pc to the faulting instruction, so that the state is the same as before the fetch
+    fn fault<R>(&mut self, address: usize, error: ExecutionError) -> Result<R, ExecutionError> {
+        self.pc = address;
+        Err(error)
+    }
+
+    ///
+    /// Decodes the words around center for a disassembly pane: before words in front of it, the word at center and after
+    /// words behind it. Addresses are 2-byte aligned (center is rounded down) and the window is cut off at the start
+    /// and the end of memory.
+    ///
+    /// Returns (address, instruction) for every word in the window.
+    pub fn disasm_window(&self, center: usize, before: usize, after: usize) -> Vec<(usize, Instruction)> {
+        let center = center & !1;
+        let start = center.saturating_sub(2 * before);
+        let end = (center + 2 * after).min(self.memory.size().saturating_sub(2));
+        (start..=end)
+            .step_by(2)
+            .map(|addr| (addr, Instruction::decode((self.memory.read(addr) as u16) << 8 | self.memory.read(addr + 1) as u16)))
+            .collect()
+    }
+
+    ///
+    /// Returns true if the instruction at pc jumps to itself, the usual way chip8 programs end.
+    /// Executing further instructions does not change the state anymore, apart from the timers.
+    pub fn is_halted(&self) -> bool {
+        self.peek_instruction() == Instruction::Jump { nnn: self.pc as u16 }
+    }
+
+    ///
+    /// Decodes the word at addr, e.g. for a debugger navigating memory. Unlike [State::peek_instruction] the address does
+    /// not wrap around: the last byte of memory and addresses outside of it return [Instruction::Invalid].
+    pub fn instruction_at(&self, addr: usize) -> Instruction {
+        if addr.saturating_add(2) > self.memory.size() {
+            return Instruction::Invalid;
+        }
+        Instruction::decode((self.memory.read(addr) as u16) << 8 | self.memory.read(addr + 1) as u16)
+    }
+
+    ///
+    /// Decodes the instruction at pc without executing it.
+    pub fn peek_instruction(&self) -> Instruction {
+        let mask = self.address_mask();
+        let op_code = (self.memory.read(self.pc & mask) as u16) << 8 | self.memory.read((self.pc + 1) & mask) as u16;
+        Instruction::decode(op_code)
+    }
+
+    ///
+    /// Runs up to budget instructions. Before each instruction on_step is called with the state and the instruction
+    /// about to be executed, its [DebugAction] decides whether the instruction is executed.
+    ///
+    /// This is the single entry point for tracing, conditional breakpoints (e.g. stop when V3 == 5) and instruction budgets.
+    ///
+    /// # Arguments
+    /// * 'budget' - maximum number of instructions to execute
+    /// * 'on_step' - called before every instruction
+    pub fn run_debug(
+        &mut self,
+        budget: usize,
+        mut on_step: impl FnMut(&Self, &Instruction) -> DebugAction,
+    ) -> Result<DebugRun, ExecutionError> {
+        for steps in 0..budget {
+            let instruction = self.peek_instruction();
+            let stop = match on_step(self, &instruction) {
+                DebugAction::Continue => None,
+                DebugAction::Pause => Some(DebugStop::Paused),
+                DebugAction::Abort => Some(DebugStop::Aborted),
+            };
+            if let Some(stop) = stop {
+                return Ok(DebugRun {
+                    steps,
+                    stop,
+                    stack_depth: self.stack_depth(),
+                });
+            }
+            self.execute()?;
+        }
+        Ok(DebugRun {
+            steps: budget,
+            stop: DebugStop::BudgetExhausted,
+            stack_depth: self.stack_depth(),
+        })
+    }
+
+    ///
+    /// Executes n instructions in a tight loop, with the same result as n calls to [State::execute].
+    ///
+    /// A burst does not hold any peripheral across instructions: with [State] every instruction that uses a peripheral
+    /// locks it for each access. A burst without any locking needs a [Core] that owns its peripherals.
+    ///
+    /// Returns how many instructions were executed. On an error it returns how many were executed before it together
+    /// with the error, those instructions stay executed.
+    pub fn execute_burst(&mut self, n: usize) -> Result<usize, (usize, ExecutionError)> {
+        for executed in 0..n {
+            self.execute().map_err(|e| (executed, e))?;
+        }
+        Ok(n)
+    }
+
+    ///
+    /// Runs the machine for the given wall-clock time: executes the instructions and ticks the timers that fall into
+    /// duration according to clock, the timer ticks are spread evenly between the instructions. Fractions of an
+    /// instruction or tick are carried over to the next call, so many short calls run as fast as one long call.
+    ///
+    /// This is the catch up after a stalled frame as well as the main loop of front-ends without a fixed step.
+    ///
+    /// # Arguments
+    /// * 'duration' - the elapsed time
+    /// * 'clock' - instruction and timer frequencies
+    pub fn run_for(&mut self, duration: core::time::Duration, clock: &Clock) -> Result<(), ExecutionError> {
+        const NANOS_PER_SECOND: u128 = 1_000_000_000;
+        self.cpu_phase += duration.as_nanos() * clock.cpu_hz as u128;
+        self.timer_phase += duration.as_nanos() * clock.timer_hz as u128;
+        let instructions = self.cpu_phase / NANOS_PER_SECOND;
+        let ticks = self.timer_phase / NANOS_PER_SECOND;
+        self.cpu_phase %= NANOS_PER_SECOND;
+        self.timer_phase %= NANOS_PER_SECOND;
+
+        let mut ticked = 0;
+        for executed in 1..=instructions {
+            self.execute()?;
+            while ticked < ticks * executed / instructions {
+                self.tick_timers();
+                ticked += 1;
+            }
+        }
+        // without instructions in this slice the ticks still have to happen
+        for _ in ticked..ticks {
+            self.tick_timers();
+        }
+        Ok(())
+    }
+
+    ///
+    /// Executes one instruction and ticks the timers as often as one instruction at clock's frequencies requires,
+    /// for front-ends that call a single function per loop iteration. The fraction of a tick is accumulated, at 700Hz
+    /// and 60Hz every 700 steps tick the timers exactly 60 times.
+    pub fn step_with_timers(&mut self, clock: &Clock) -> Result<StepOutcome, ExecutionError> {
+        let outcome = self.execute()?;
+        self.step_timer_phase += clock.timer_hz as u64;
+        while clock.cpu_hz > 0 && self.step_timer_phase >= clock.cpu_hz as u64 {
+            self.step_timer_phase -= clock.cpu_hz as u64;
+            self.tick_timers();
+        }
+        Ok(outcome)
+    }
+
+    ///
+    /// Runs steps instructions and returns a hash of the (pc, opcode, VF) of every step, for regression tests in CI:
+    /// any change in behavior changes the hash. An error ends the run and is part of the hash.
+    ///
+    /// The hash is FNV-1a and stable across platforms and versions. Use [State::with_rng_seed] for ROMs with Rand.
+    pub fn trace_hash(&mut self, steps: usize) -> u64 {
+        fn feed(hash: u64, bytes: &[u8]) -> u64 {
+            bytes.iter().fold(hash, |hash, byte| (hash ^ *byte as u64).wrapping_mul(0x0000_0100_0000_01B3))
+        }
+
+        let mut hash = 0xCBF2_9CE4_8422_2325;
+        for _ in 0..steps {
+            match self.step_describe() {
+                Ok((address, op_code, _)) => {
+                    hash = feed(hash, &(address as u16).to_be_bytes());
+                    hash = feed(hash, &op_code.to_be_bytes());
+                    hash = feed(hash, &[self.gp_registers[0xF]]);
+                },
+                Err(e) => {
+                    hash = feed(hash, format!("{}", e).as_bytes());
+                    break;
+                },
+            }
+        }
+        hash
+    }
+
+    ///
+    /// Executes instructions until pred returns true or max instructions were executed.
+    ///
+    /// Returns whether the condition was met. pred is checked before the first and after every instruction.
+    ///
+    /// # Arguments
+    /// * 'max' - maximum number of instructions to execute
+    /// * 'pred' - condition to wait for, e.g. a ROM test that finished writing its result
+    pub fn run_until(&mut self, max: usize, pred: impl Fn(&Self) -> bool) -> Result<bool, ExecutionError> {
+        for _ in 0..max {
+            if pred(self) {
+                return Ok(true);
+            }
+            self.execute()?;
+        }
+        Ok(pred(self))
+    }
+
+    ///
+    /// Returns the sum of the [Instruction::cycle_cost] of all executed instructions.
+    /// Front-ends can use it to pace frames by VIP machine cycles instead of a flat number of instructions.
+    pub fn machine_cycles(&self) -> u64 {
+        self.machine_cycles
+    }
+
+    ///
+    /// Returns the whole memory, e.g. for hashing or exporting it. The length is the configured [MemorySize].
+    /// Borrowed if the backend provides [Memory::as_slice], otherwise copied with [Memory::read].
+    pub fn memory(&self) -> Cow<'_, [u8]> {
+        match self.memory.as_slice() {
+            Some(bytes) => Cow::Borrowed(bytes),
+            None => Cow::Owned((0..self.memory.size()).map(|addr| self.memory.read(addr)).collect()),
+        }
+    }
+
+    ///
+    /// Returns all addresses at which needle occurs in memory, e.g. to locate sprite tables. An empty needle is never found.
+    pub fn find_pattern(&self, needle: &[u8]) -> Vec<usize> {
+        if needle.is_empty() {
+            return Vec::new();
+        }
+        self.memory()
+            .windows(needle.len())
+            .enumerate()
+            .filter(|(_, window)| *window == needle)
+            .map(|(addr, _)| addr)
+            .collect()
+    }
+
+    ///
+    /// Replaces the display, e.g. to switch to a hi-res display or a fading buffer while a ROM is running.
+    /// Later instructions draw to the new display.
+    ///
+    /// # Arguments
+    /// * 'display' - the new display
+    /// * 'clear' - clear the new display, otherwise its content is kept
+    pub fn set_display(&mut self, mut display: D, clear: bool) {
+        if clear {
+            display.clear();
+        }
+        self.display = display;
+        self.needs_present = true;
+    }
+
+    ///
+    /// Switches the display to the 64x32 lo-res mode and clears it, see [Display::set_resolution].
+    /// [State::initialize] does this as well if the previous ROM left the display in hi-res.
+    pub fn set_lores(&mut self) {
+        self.display.set_resolution(64, 32);
+        self.hires = false;
+        self.needs_present = true;
+    }
+
+    ///
+    /// Switches the display to the 128x64 SUPER-CHIP hi-res mode and clears it. In hi-res DXY0 draws 16x16 sprites.
+    pub fn set_hires(&mut self) {
+        self.display.set_resolution(128, 64);
+        self.hires = true;
+        self.needs_present = true;
+    }
+
+    ///
+    /// Returns true if the display is in hi-res mode, see [State::set_hires].
+    pub fn is_hires(&self) -> bool {
+        self.hires
+    }
+
+    ///
+    /// Returns true if the display changed (CLS, DRW, a new display) since the last [State::present_done].
+    /// A render thread presenting at a fixed rate can use it to skip frames without changes and to coalesce draws.
+    pub fn needs_present(&self) -> bool {
+        self.needs_present
+    }
+
+    ///
+    /// Resets the flag returned by [State::needs_present], call it after presenting the display.
+    pub fn present_done(&mut self) {
+        self.needs_present = false;
+    }
+
+    ///
+    /// Returns true if any draw collided since the last [State::clear_frame_collision], even if VF was overwritten since.
+    pub fn frame_collision(&self) -> bool {
+        self.frame_collision
+    }
+
+    ///
+    /// Resets the flag returned by [State::frame_collision], usually called by the host at every frame boundary.
+    pub fn clear_frame_collision(&mut self) {
+        self.frame_collision = false;
+    }
+
+    ///
+    /// Returns the display, e.g. to render the pixels of a [Core] that owns it.
+    pub fn display(&self) -> &D {
+        &self.display
+    }
+
+    ///
+    /// Returns the display for changes by the host, e.g. to switch the colors of a display the [Core] owns.
+    pub fn display_mut(&mut self) -> &mut D {
+        &mut self.display
+    }
+
+    ///
+    /// Returns the keypad, e.g. to press keys on a keypad the [Core] owns.
+    pub fn keypad_mut(&mut self) -> &mut K {
+        &mut self.keypad
+    }
+
+    ///
+    /// Returns (width, height) of the display, see [Display::dimensions].
+    pub fn display_dimensions(&self) -> (usize, usize) {
+        self.display.dimensions()
+    }
+
+    ///
+    /// Returns the 16 glyphs of the font as they are in memory, starting at the font address of
+    /// [State::initialize_with_font_at]. Cut off at the end of memory.
+    pub fn font_bytes(&self) -> Vec<u8> {
+        let start = self.font_address.min(self.memory.size());
+        let end = (start + 16 * FONT_CHARACTER_BYTES).min(self.memory.size());
+        (start..end).map(|addr| self.memory.read(addr)).collect()
+    }
+
+    ///
+    /// Returns the pixels of the font glyph of digit as it is in memory, e.g. to render the font in tools. The default
+    /// font only uses the left 4 columns. Only the lower nibble of digit is used, like SetFontI does.
+    pub fn font_glyph(&self, digit: u8) -> [[bool; 8]; 5] {
+        let start = (digit & 0x0F) as usize * FONT_CHARACTER_BYTES;
+        let mut glyph = [[false; 8]; 5];
+        for (row, byte) in glyph.iter_mut().zip(self.font_bytes().iter().skip(start)) {
+            *row = u8_to_bool_array(*byte);
+        }
+        glyph
+    }
+
+    ///
+    /// Returns the hex digit (0x0 .. 0xF) of the font glyph I points to, e.g. after SetFontI.
+    /// Returns `None` if I does not point to the start of a glyph.
+    pub fn font_digit_at_index(&self) -> Option<u8> {
+        let offset = (self.index_reg as usize).checked_sub(self.font_address)?;
+        if offset >= 16 * FONT_CHARACTER_BYTES || offset % FONT_CHARACTER_BYTES != 0 {
+            return None;
+        }
+        Some((offset / FONT_CHARACTER_BYTES) as u8)
+    }
+
+    ///
+    /// Returns the rows of the n byte sprite at addr as pixels, without drawing it. Rows beyond the end of memory are left out.
+    pub fn preview_sprite(&self, addr: usize, n: u8) -> Vec<[bool; 8]> {
+        let start = addr.min(self.memory.size());
+        let end = (start + n as usize).min(self.memory.size());
+        (start..end).map(|addr| u8_to_bool_array(self.memory.read(addr))).collect()
+    }
+
+    ///
+    /// Returns the index register I.
+    pub fn index_register(&self) -> u16 {
+        self.index_reg
+    }
+
+    ///
+    /// Sets the index register I, e.g. to point a Draw at a sprite without a MovI. The value wraps at the memory size.
+    pub fn set_index_register(&mut self, value: u16) {
+        self.index_reg = (value as usize & self.address_mask()) as u16;
+    }
+
+    ///
+    /// Returns the return addresses on the stack, the innermost call last. The depth is [State::stack_depth].
+    pub fn call_stack(&self) -> &[usize] {
+        &self.stack
+    }
+
+    ///
+    /// Returns the number of return addresses on the stack.
+    pub fn stack_depth(&self) -> usize {
+        self.stack.len()
+    }
+
+    // execute the next instruction located at pc
+    pub fn execute(&mut self) -> Result<StepOutcome, ExecutionError> {
+        self.fetch_and_execute().map(|(_, _, _, outcome)| outcome)
+    }
+
+    ///
+    /// Executes the next instruction like [State::execute] and returns what was executed, e.g. for a REPL debugger.
+    ///
+    /// Returns (address, opcode, instruction) of the executed instruction, the address is the pc before the fetch.
+    pub fn step_describe(&mut self) -> Result<(usize, u16, Instruction), ExecutionError> {
+        self.fetch_and_execute().map(|(address, op_code, instruction, _)| (address, op_code, instruction))
+    }
+
+    fn fetch_and_execute(&mut self) -> Result<(usize, u16, Instruction, StepOutcome), ExecutionError> {
+        let address = self.pc;
+        let op_code = self.fetch()?;
+
+        //println!("{:#06x}", op_code);
+        // Decode
+        let instruction  = Instruction::decode(op_code);
+
+        //println!("{:?}", instruction);
+
+        let outcome = self.execute_instruction(instruction, op_code, address)?;
+        Ok((address, op_code, instruction, outcome))
+    }
+
+    ///
+    /// Reads the opcode at pc and advances the pc by 2, the first stage of [State::execute]. Together with
+    /// [Instruction::decode] and [State::execute_decoded] a debugger can stop between the stages.
+    ///
+    /// Returns an error and leaves the pc unchanged if the pc is misaligned or out of bounds with the strict checks.
+    pub fn fetch(&mut self) -> Result<u16, ExecutionError> {
+        if self.require_aligned_pc && !self.pc.is_multiple_of(2) {
+            return Err(ExecutionError::MisalignedPc(self.pc));
+        }
+        self.check_memory(self.pc, 2)?;
+
+        let mask = self.address_mask();
+
+        // chip8 uses big endian
+        let upper = self.memory.read(self.pc & mask);
+        let lower = self.memory.read((self.pc + 1) & mask);
+
+        let op_code = (upper as u16) << 8 | (lower as u16);
+        self.fetched = (self.pc, op_code);
+        // keep in mind that the pc is incremented here, important for some instructions
+        self.pc += 2;
+        Ok(op_code)
+    }
+
+    ///
+    /// Executes an instruction returned by [State::fetch] and [Instruction::decode], the last stage of [State::execute].
+    /// The instruction may differ from the fetched one, e.g. when a debugger patches it. Errors reset the pc to the
+    /// address of the last fetch.
+    pub fn execute_decoded(&mut self, instruction: Instruction) -> Result<StepOutcome, ExecutionError> {
+        let (address, fetched) = self.fetched;
+        let op_code = if Instruction::decode(fetched) == instruction {
+            fetched
+        } else {
+            instruction.encode().unwrap_or(fetched)
+        };
+        self.execute_instruction(instruction, op_code, address)
+    }
+
+    ///
+    /// Decodes and executes op_code as if it was located at pc, without reading memory.
+    ///
+    /// The pc is not advanced by a fetch: instructions that do not touch the pc leave it unchanged, skips advance it
+    /// by 2 relative to the current pc, jumps and calls set it (a call pushes the current pc as return address) and
+    /// WaitKey without a pressed key leaves it unchanged.
+    pub fn execute_opcode(&mut self, op_code: u16) -> Result<StepOutcome, ExecutionError> {
+        let address = self.pc;
+        self.execute_instruction(Instruction::decode(op_code), op_code, address)
+    }
+
+    // executes an already fetched instruction, address is where it was fetched from
+    fn execute_instruction(&mut self, instruction: Instruction, op_code: u16, address: usize) -> Result<StepOutcome, ExecutionError> {
+        if !self.enabled_instructions.contains(instruction.family()) {
+            return self.fault(address, ExecutionError::DisabledInstruction(op_code));
+        }
+        if let Some(hook) = &mut self.uninitialized_read_hook {
+            let unwritten = instruction.registers_read(&self.quirks) & !self.written_registers;
+            for register in (0..16).filter(|r| unwritten & (1 << r) != 0) {
+                hook(register, address);
+            }
+        }
+        if !matches!(instruction, Instruction::WaitKey { .. }) {
+            self.wait_spins = 0;
+        }
+
+        let mut outcome = StepOutcome::Executed;
+        let mut vf_written = instruction.writes_vf();
+        match instruction {
+            Instruction::Cls => {
+                self.display.clear();
+                self.needs_present = true;
+            },
+            Instruction::Rts => match (self.stack.pop(), self.stack_policy) {
+                (Some(return_address), _) => self.pc = return_address,
+                (None, StackPolicy::Panic) => panic!("return with empty stack at {:#05x}", address),
+                (None, StackPolicy::Error) => return self.fault(address, ExecutionError::StackUnderflow),
+                (None, StackPolicy::Saturate) => (),
+            },
+            Instruction::Jump{nnn} => self.pc = nnn as usize,
+            Instruction::Call { nnn } => {
+                if self.stack_limit.is_some_and(|limit| self.stack.len() >= limit) {
+                    match self.stack_policy {
+                        StackPolicy::Panic => panic!("stack overflow at {:#05x}", address),
+                        StackPolicy::Error => return self.fault(address, ExecutionError::StackOverflow),
+                        StackPolicy::Saturate => (),
+                    }
+                } else {
+                    self.stack.push(self.pc);
+                }
+                self.pc = nnn as usize;
+            },
+            Instruction::SkipEqConst { x, nn } => if self.gp_registers[x as usize] == nn {self.skip_next();},
+            Instruction::SkipNeqConst { x, nn } => if self.gp_registers[x as usize] != nn {self.skip_next();},
+            Instruction::SkipEq { x, y } => if self.gp_registers[x as usize] == self.gp_registers[y as usize] {self.skip_next()},
+            Instruction::MovConst { x, nn } => self.gp_registers[x as usize] = nn,
+            Instruction::AddConst { x, nn } => self.gp_registers[x as usize] = (self.gp_registers[x as usize] as u16 + nn as u16) as u8, // properly handle overflow, as u8 should truncate
+            Instruction::Mov { x, y } => self.gp_registers[x as usize] = self.gp_registers[y as usize],
+            Instruction::Or { x, y } => self.gp_registers[x as usize] |= self.gp_registers[y as usize],
+            Instruction::And { x, y } => self.gp_registers[x as usize] &= self.gp_registers[y as usize],
+            Instruction::Xor { x, y } => self.gp_registers[x as usize] ^= self.gp_registers[y as usize],
+            Instruction::Add { x, y } => {
+                let sum = self.gp_registers[x as usize] as u16 + self.gp_registers[y as usize] as u16;
+                self.write_result_and_flag(x, sum as u8, (sum > 0xFF) as u8);
+            },
+            Instruction::SubXY { x, y } => {
+                let x_val:u8 = self.gp_registers[x as usize];
+                let y_val:u8 = self.gp_registers[y as usize];
+
+
+                if x_val > y_val{
+                    self.write_result_and_flag(x, x_val - y_val, 1);
+                } else {
+                    // TODO: check if this is the right behavior
+                    self.write_result_and_flag(x, 0xFF - (y_val - x_val), 0);
+                }
+            },
+            Instruction::RightShift { x, y } => {
+                let source = if self.quirks.shift_uses_vy { self.gp_registers[y as usize] } else { self.gp_registers[x as usize] };
+                self.write_result_and_flag(x, source >> 1, source & 0x01);
+            },
+            Instruction::SubYX { x, y } =>{
+                let x_val:u8 = self.gp_registers[x as usize];
+                let y_val:u8 = self.gp_registers[y as usize];
+
+
+                if y_val > x_val{
+                    self.write_result_and_flag(x, y_val - x_val, 1);
+                } else {
+                    // TODO: check if this is the right behavior
+                    self.write_result_and_flag(x, 0xFF - (x_val - y_val), 0);
+                }
+            },
+            Instruction::LeftShift { x, y } => {
+                let source = if self.quirks.shift_uses_vy { self.gp_registers[y as usize] } else { self.gp_registers[x as usize] };
+                self.write_result_and_flag(x, source << 1, source >> 7);
+            },
+            Instruction::SkipNeq { x, y } => {
+                if self.gp_registers[x as usize] != self.gp_registers[y as usize] {
+                    self.skip_next();
+                }
+            },
+            Instruction::MovI { nnn } => self.index_reg = nnn,
+            Instruction::JumpIndexed { nnn } => self.pc = nnn as usize + self.gp_registers[0] as usize,
+            
+            Instruction::Rand { x, nn } => self.gp_registers[x as usize] = self.rng.next_byte() & nn,
+
+            Instruction::Draw { x, y, n } => {
+                // DXYN draws N rows of 8 pixels (1 ..= 15). DXY0 draws a 16x16 sprite of 32 bytes in hi-res
+                // (SUPER-CHIP), in lo-res it depends on the quirk
+                let wide = n == 0 && self.hires;
+                let len = match n {
+                    0 if wide => 32,
+                    0 if self.quirks.lores_dxy0_draws_16_rows => 16,
+                    n => n as usize,
+                };
+                if let Err(e) = self.check_memory(self.index_reg as usize, len) {
+                    return self.fault(address, e);
+                }
+                // the sprite is cut off at the end of memory
+                let start = (self.index_reg as usize).min(self.memory.size());
+                let end = (start + len).min(self.memory.size());
+                let mut buffer = [0u8; 32];
+                for (byte, addr) in buffer.iter_mut().zip(start..end) {
+                    *byte = self.memory.read(addr);
+                }
+                let sprite = &buffer[..end - start];
+                let (vx, vy) = (self.gp_registers[x as usize], self.gp_registers[y as usize]);
+                if let Some(hook) = &mut self.draw_interference_hook {
+                    hook();
+                }
+                let display = &mut self.display;
+                let (width, height) = display.dimensions();
+                // None for a misconfigured display without pixels or a start position the display clips
+                let origin = display.sprite_origin(vx, vy);
+                let res = if let Some((origin_x, _)) = origin.filter(|_| wide) {
+                    // rows are 2 bytes, drawn as two 8 pixel wide halves. The right half is clipped at the right edge
+                    let (mut left, mut right) = ([0u8; 16], [0u8; 16]);
+                    for (i, byte) in sprite.iter().enumerate() {
+                        if i % 2 == 0 {
+                            left[i / 2] = *byte;
+                        } else {
+                            right[i / 2] = *byte;
+                        }
+                    }
+                    let (left, right) = (&left[..sprite.len().div_ceil(2)], &right[..sprite.len() / 2]);
+                    let left_collision = display.modify(left, left.len() as u8, vx, vy);
+                    let right_x = origin_x + 8;
+                    let right_collision = right_x < width && display.modify(right, right.len() as u8, right_x as u8, vy);
+                    left_collision || right_collision
+                } else {
+                    origin.is_some() && display.modify(sprite, sprite.len() as u8, vx, vy)
+                };
+                // what was visible, a sprite the display does not draw at all counts as clipped
+                let rows = if wide { sprite.len().div_ceil(2) } else { sprite.len() };
+                let sprite_width = if wide { 16 } else { 8 };
+                let (rows_drawn, clipped) = match origin {
+                    Some((origin_x, origin_y)) => {
+                        let rows_drawn = rows.min(height.saturating_sub(origin_y));
+                        (rows_drawn, rows_drawn < rows || sprite_width > width.saturating_sub(origin_x))
+                    },
+                    None => (0, rows > 0),
+                };
+                outcome = StepOutcome::Drew(DrawInfo {
+                    collided: res,
+                    rows_drawn: rows_drawn as u8,
+                    clipped,
+                });
+                self.frame_collision |= res;
+                self.needs_present = true;
+                if res{
+                    self.gp_registers[0xF] = 1;
+                } else if self.quirks.draw_sets_vf_only_on_collision {
+                    vf_written = false;
+                } else {
+                    self.gp_registers[0xF] = 0;
+                }
+            },
+
+            Instruction::SkipKeyEq { x } => {
+                let key = self.pressed_key();
+                if let Some(k) = key {
+                    if k == self.gp_registers[x as usize]{
+                        self.skip_next();
+                    }
+                }
+            },
+
+            Instruction::SkipKeyNeq { x } => {
+                let key = self.pressed_key();
+                if key.is_none() {
+                    self.skip_next();
+                } else if let Some(k) = key {
+                    if k != self.gp_registers[x as usize] {
+                        self.skip_next();
+                    }
+                }
+            }
+            Instruction::GetDelayTimer { x } => self.gp_registers[x as usize] = self.delay_timer.get(),
+            // just reexecutes the instruction if no key was pressed
+            Instruction::WaitKey { x } => {
+                let key = self.pressed_key();
+                if let Some(k) = key {
+                    self.gp_registers[x as usize] = k;
+                    self.wait_spins = 0;
+                } else {
+                    self.pc = address;
+                    vf_written = false;
+                    self.wait_spins += 1;
+                    if self.max_wait_spins.is_some_and(|max| self.wait_spins >= max) {
+                        outcome = StepOutcome::StalledOnKey;
+                    }
+                }
+            },
+            Instruction::SetDelayTimer { x } => self.delay_timer.set(self.gp_registers[x as usize]),
+            Instruction::SetSoundTimer { x } => {
+                // values below the minimum are too short to be heard and count as 0
+                let value = match self.gp_registers[x as usize] {
+                    value if value < self.min_sound_timer => 0,
+                    value => value,
+                };
+                self.sound_timer.start(value);
+                if value > 0 {
+                    if let Some(hook) = &mut self.sound_hook {
+                        hook(true);
+                    }
+                }
+            },
+            // wraps at the memory size, 0x0FFF + 1 is 0 with 4KiB but 0x1000 with 64KiB
+            Instruction::AddI { x } => {
+                let sum = self.index_reg as usize + self.gp_registers[x as usize] as usize;
+                // the flag is computed from the unmasked sum, VX was read before in case x is F
+                if self.quirks.add_i_sets_overflow {
+                    self.gp_registers[0xF] = (sum > self.address_mask()) as u8;
+                    vf_written = true;
+                }
+                self.index_reg = (sum & self.address_mask()) as u16;
+            },
+            // just consider the lower nibble of the register
+            Instruction::SetFontI { x } => self.index_reg = (self.font_address + FONT_CHARACTER_BYTES * (self.gp_registers[x as usize] & 0x0F) as usize) as u16,
+            Instruction::BCD { x } => {
+                if let Err(e) = self.check_memory(self.index_reg as usize, 3) {
+                    return self.fault(address, e);
+                }
+                if self.strict_memory && (self.index_reg as usize) < PROGRAM_START {
+                    return self.fault(address, ExecutionError::ReservedMemoryWrite(self.index_reg as usize));
+                }
+                let mask = self.address_mask();
+                let index = self.index_reg as usize;
+                let mut x_val = self.gp_registers[x as usize];
+                self.write_memory((index + 2) & mask, x_val % 10);
+                x_val /= 10;
+                self.write_memory((index + 1) & mask, x_val % 10);
+                x_val /= 10;
+                self.write_memory(index & mask, x_val);
+                
+            },
+            // without the strict checks V0..VX are accessed as one block that continues at address 0 when it runs
+            // over the end of memory
+            Instruction::RegDump { x } => {
+                debug_assert!(x < 16, "RegDump with x = {}", x);
+                let x = x.min(0xF);
+                if let Err(e) = self.check_memory(self.index_reg as usize, x as usize + 1) {
+                    return self.fault(address, e);
+                }
+                let mask = self.address_mask();
+                for i in 0..=(x as usize){
+                    self.write_memory((self.index_reg as usize + i ) & mask, self.gp_registers[i]);
+                }
+                self.increment_index_after_load_store(x);
+            },
+            Instruction::RegLoad { x } => {
+                debug_assert!(x < 16, "RegLoad with x = {}", x);
+                let x = x.min(0xF);
+                if let Err(e) = self.check_memory(self.index_reg as usize, x as usize + 1) {
+                    return self.fault(address, e);
+                }
+                let mask = self.address_mask();
+                for i in 0..=(x as usize){
+                    self.gp_registers[i] = self.memory.read((self.index_reg as usize + i ) & mask);
+                }
+                self.increment_index_after_load_store(x);
+            },
+
+            // permissive mode just skips the opcode
+            Instruction::Invalid => {
+                // the handler is taken out while it runs since it gets the whole state
+                let consumed = match self.extension_handler.take() {
+                    Some(mut handler) => {
+                        let consumed = handler(self, op_code);
+                        self.extension_handler.get_or_insert(handler);
+                        consumed
+                    },
+                    None => false,
+                };
+                if !consumed && self.invalid_opcode_errors {
+                    return self.fault(address, ExecutionError::InvalidOpcode(op_code));
+                }
+            }
+        }
+        // a flag that was left alone, like VF of a draw without collision under the quirk, is not written
+        let mut written = instruction.registers_written(&self.quirks);
+        if !vf_written {
+            written &= !(1 << 0xF);
+        }
+        self.written_registers |= written;
+        self.machine_cycles += instruction.cycle_cost() as u64;
+        if let Some(log) = &mut self.opcode_log {
+            log.push((address, op_code));
+        }
+        if vf_written {
+            if let Some(watch) = &mut self.vf_watch {
+                watch(self.gp_registers[0xF], instruction.mnemonic());
+            }
+        }
+        Ok(outcome)
+    }
+}
+
+
+
+/// Explains why an opcode does not decode to an instruction, see [Instruction::decode_detailed].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeError {
+    /// 0NNN calls a machine code routine of the COSMAC VIP, which is not supported
+    MachineCodeCall { nnn: u16 },
+    /// the lowest nibble selects no instruction of the family (5XY?, 8XY?, 9XY?), family is the first nibble
+    BadLowNibble { family: u8 },
+    /// the lower byte selects no instruction of the family (EX??, FX??), family is the first nibble
+    ReservedPattern { family: u8 },
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::MachineCodeCall { nnn } => write!(f, "machine code call to {:#05x} is not supported", nnn),
+            DecodeError::BadLowNibble { family } => write!(f, "unknown last nibble for {:X}XY? instruction", family),
+            DecodeError::ReservedPattern { family } => write!(f, "unknown lower byte for {:X}X?? instruction", family),
+        }
+    }
+}
+
+impl core::error::Error for DecodeError {}
+
+/// A set of instruction families, see [State::set_enabled_instructions]. Combine families with `|`, remove them with
+/// `& !`, e.g. `InstructionSet::ALL & !InstructionSet::DISPLAY`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InstructionSet(u16);
+
+impl InstructionSet {
+    /// CLS, DRW
+    pub const DISPLAY: Self = Self(1 << 0);
+    /// JP, CALL, RET, JP V0
+    pub const FLOW: Self = Self(1 << 1);
+    /// 3XNN, 4XNN, 5XY0, 9XY0
+    pub const SKIP: Self = Self(1 << 2);
+    /// 6XNN, 7XNN
+    pub const CONST: Self = Self(1 << 3);
+    /// 8XY0 .. 8XYE
+    pub const ALU: Self = Self(1 << 4);
+    /// ANNN, FX1E, FX29
+    pub const INDEX: Self = Self(1 << 5);
+    /// CXNN
+    pub const RAND: Self = Self(1 << 6);
+    /// EX9E, EXA1, FX0A
+    pub const KEYPAD: Self = Self(1 << 7);
+    /// FX07, FX15, FX18
+    pub const TIMERS: Self = Self(1 << 8);
+    /// FX33, FX55, FX65
+    pub const MEMORY: Self = Self(1 << 9);
+    pub const ALL: Self = Self((1 << 10) - 1);
+    pub const EMPTY: Self = Self(0);
+
+    /// Returns true if all families of other are in the set.
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl Default for InstructionSet {
+    fn default() -> Self {
+        Self::ALL
+    }
+}
+
+impl core::ops::BitOr for InstructionSet {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+impl core::ops::BitAnd for InstructionSet {
+    type Output = Self;
+
+    fn bitand(self, rhs: Self) -> Self {
+        Self(self.0 & rhs.0)
+    }
+}
+
+impl core::ops::Not for InstructionSet {
+    type Output = Self;
+
+    fn not(self) -> Self {
+        Self(!self.0 & Self::ALL.0)
+    }
+}
+
+/// What executing an [Instruction] touches, see [Instruction::effects].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct InstructionEffects {
+    /// bitmask of the registers read, bit i is Vi
+    pub registers_read: u16,
+    /// bitmask of the registers written, bit i is Vi
+    pub registers_written: u16,
+    /// memory at I is read (not counting the fetch)
+    pub reads_memory: bool,
+    /// memory at I is written
+    pub writes_memory: bool,
+    pub display: bool,
+    pub keypad: bool,
+    /// the delay timer or the sound timer
+    pub timers: bool,
+}
+
+/// The fields of an [Instruction], see [Instruction::operands]. Fields the instruction does not have are None.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Operands {
+    /// register index from the second nibble
+    pub x: Option<u8>,
+    /// register index from the third nibble
+    pub y: Option<u8>,
+    /// the fourth nibble
+    pub n: Option<u8>,
+    /// the lower byte
+    pub nn: Option<u8>,
+    /// the lower 12 bits, an address
+    pub nnn: Option<u16>,
+}
+
+// Mnemonics are (mostly) taken from: http://www.emulator101.com/chip-8-instruction-set.html
+// also https://en.wikipedia.org/wiki/CHIP-8
+// X: second nibble of instruction. Used to look up one of the 16 registers
+// Y: third nibble of instruction. Used to look up one of the 16 registers
+// N: The *fourth* nibble
+// NN: second byte, immediate 8-bit number
+// NNN: second, third and fourth nibble, immediate 12-bit address
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Instruction {
+    // 0NNN, Instruction 0NNN calls a machine code routine (RCA 1802 for COSMAC VIP), I won't implement this instruction
+    // use Invalid for this Instruction
+    Invalid,
+    // 00E0, clear screen
+    Cls,
+    // 00EE, return from subroutine
+    Rts,
+    // 1NNN, absolute jump to NNN
+    Jump { nnn: u16 },
+    // 2NNN, jump to subroutine at NNN (push address to stack, change pc)
+    Call { nnn: u16 },
+    // 3XNN, skip next instruction if Vx equals NN
+    SkipEqConst { x: u8, nn: u8 },
+    // 4XNN, skip next instruction if Vx does not equal NN
+    SkipNeqConst { x: u8, nn: u8 },
+    // 5XY0, skips the next instruction if VX equals VY
+    SkipEq { x: u8, y: u8 },
+    // 6XNN, Sets VX to NN.
+    MovConst { x: u8, nn: u8 },
+    // 7XNN, Adds NN to VX (carry flag is not changed)
+    AddConst { x: u8, nn: u8 },
+    // 8XY0, Sets VX to the value of VY.
+    Mov { x: u8, y: u8 },
+    // 8XY1, Sets VX to VX or VY. (bitwise OR operation)
+    Or { x: u8, y: u8 },
+    // 8XY2, Sets VX to VX and VY. (bitwise AND operation)
+    And { x: u8, y: u8 },
+    // 8XY3, Sets VX to VX xor VY
+    Xor { x: u8, y: u8 },
+    // 8XY4, Adds VY to VX. VF is set to 1 when there's a carry, and to 0 when there is not.
+    Add { x: u8, y: u8 },
+    // 8XY5, VY is subtracted from VX. VF is set to 0 when there's a borrow, and 1 when there is not.
+    SubXY { x: u8, y: u8 },
+    // 8XY6, Stores the least significant bit of VX in VF and then shifts VX to the right by 1 (ambiguous see chip8 guide)
+    RightShift { x: u8, y: u8 },
+    // 8XY7, Sets VX to VY minus VX. VF is set to 0 when there's a borrow, and 1 when there is not.
+    SubYX { x: u8, y: u8 },
+    // 8XYE, Stores the most significant bit of VX in VF and then shifts VX to the left by 1
+    LeftShift { x: u8, y: u8 },
+    // 9XY0, Skips the next instruction if VX does not equal VY
+    SkipNeq { x: u8, y: u8 },
+    // ANNN, Sets I to the address NNN
+    MovI { nnn: u16 },
+    // BNNN, indexed jump, jump to NNN + V0, Ambiguous 
+    JumpIndexed { nnn: u16 },
+    // CXNN, Sets VX to the result of a bitwise and operation on a random number (Typically: 0 to 255) and NN
+    Rand { x: u8, nn: u8 },
+    // DXYN, Draws a sprite at coordinate (VX, VY) that has a width of 8 pixels and a height of N pixels. Each row of 8 pixels is read as bit-coded starting from memory location I; I value does not change after the execution of this instruction. VF will be set if a screen pixel was changed
+    Draw { x: u8, y: u8, n: u8 },
+    // EX9E, Skips the next instruction if the key stored in VX is pressed
+    SkipKeyEq { x: u8 },
+    // EXA1, Skips the next instruction if the key stored in VX is not pressed
+    SkipKeyNeq { x: u8 },
+    // FX07, Sets VX to the value of the delay timer
+    GetDelayTimer { x: u8 },
+    // FX0A, A key press is awaited, and then stored in VX
+    WaitKey { x: u8 },
+    // FX15, set delay timer to VX
+    SetDelayTimer { x: u8 },
+    // FX18, Sets the sound timer to VX.
     SetSoundTimer { x: u8 },
     // FX1E, Adds VX to I. VF is not affected.
     AddI { x: u8 },
@@ -574,242 +2939,3089 @@ pub enum Instruction {
     RegLoad { x: u8 },
 }
 
-impl Instruction {
-    pub fn decode(op_code: u16) -> Instruction {
-        let nibbles = Instruction::code_to_nibble_array(op_code);
+impl Instruction {
+    pub fn decode(op_code: u16) -> Instruction {
+        let nibbles = Instruction::code_to_nibble_array(op_code);
+
+        if nibbles[0] == 0 {
+            if nibbles[1] == 0 && nibbles[2] == 0xE && nibbles[3] == 0 {
+                return Instruction::Cls;
+            } else if nibbles[1] == 0 && nibbles[2] == 0xE && nibbles[3] == 0xE {
+                return Instruction::Rts;
+            } else {
+                return Instruction::Invalid;
+            }
+        }
+
+        if nibbles[0] == 1 {
+            return Instruction::Jump {
+                nnn: Instruction::combine_nibbles(&nibbles[1..]),
+            };
+        }
+
+        if nibbles[0] == 2 {
+            return Instruction::Call {
+                nnn: Instruction::combine_nibbles(&nibbles[1..]),
+            };
+        }
+
+        if nibbles[0] == 3 {
+            return Instruction::SkipEqConst {
+                x: nibbles[1] as u8,
+                nn: Instruction::combine_nibbles(&nibbles[2..]) as u8,
+            };
+        }
+
+        if nibbles[0] == 4 {
+            return Instruction::SkipNeqConst {
+                x: nibbles[1] as u8,
+                nn: Instruction::combine_nibbles(&nibbles[2..]) as u8,
+            };
+        }
+
+        if nibbles[0] == 5 {
+            if nibbles[3] != 0 {
+                return Instruction::Invalid;
+            }
+
+            return Instruction::SkipEq {
+                x: nibbles[1] as u8,
+                y: nibbles[2] as u8,
+            };
+        }
+
+        if nibbles[0] == 6 {
+            return Instruction::MovConst {
+                x: nibbles[1] as u8,
+                nn: Instruction::combine_nibbles(&nibbles[2..]) as u8,
+            };
+        }
+
+        if nibbles[0] == 7 {
+            return Instruction::AddConst {
+                x: nibbles[1] as u8,
+                nn: Instruction::combine_nibbles(&nibbles[2..]) as u8,
+            };
+        }
+
+        if nibbles[0] == 8 {
+            let x = nibbles[1] as u8;
+            let y = nibbles[2] as u8;
+            if nibbles[3] == 0 {
+                return Instruction::Mov { x, y };
+            }
+
+            if nibbles[3] == 1 {
+                return Instruction::Or { x, y };
+            }
+
+            if nibbles[3] == 2 {
+                return Instruction::And { x, y };
+            }
+
+            if nibbles[3] == 3 {
+                return Instruction::Xor { x, y };
+            }
+
+            if nibbles[3] == 4 {
+                return Instruction::Add { x, y };
+            }
+
+            if nibbles[3] == 5 {
+                return Instruction::SubXY { x, y };
+            }
+
+            if nibbles[3] == 6 {
+                return Instruction::RightShift { x, y };
+            }
+
+            if nibbles[3] == 7 {
+                return Instruction::SubYX { x, y };
+            }
+
+            if nibbles[3] == 0xE {
+                return Instruction::LeftShift { x, y };
+            }
+        }
+
+        if nibbles[0] == 9 && nibbles[3] == 0 {
+            return Instruction::SkipNeq {
+                x: nibbles[1] as u8,
+                y: nibbles[2] as u8,
+            };
+        }
+
+        if nibbles[0] == 0xA {
+            return Instruction::MovI {
+                nnn: Instruction::combine_nibbles(&nibbles[1..]),
+            };
+        }
+
+        if nibbles[0] == 0xB {
+            return Instruction::JumpIndexed {
+                nnn: Instruction::combine_nibbles(&nibbles[1..]),
+            };
+        }
+
+        if nibbles[0] == 0xC {
+            return Instruction::Rand {
+                x: nibbles[1] as u8,
+                nn: Instruction::combine_nibbles(&nibbles[2..]) as u8,
+            };
+        }
+
+        if nibbles[0] == 0xD {
+            return Instruction::Draw {
+                x: nibbles[1] as u8,
+                y: nibbles[2] as u8,
+                n: nibbles[3] as u8,
+            };
+        }
+
+        if nibbles[0] == 0xE {
+            let x = nibbles[1] as u8;
+            if nibbles[2] == 9 && nibbles[3] == 0xE {
+                return Instruction::SkipKeyEq { x };
+            }
+
+            if nibbles[2] == 0xA && nibbles[3] == 1 {
+                return Instruction::SkipKeyNeq { x };
+            }
+        }
+
+        if nibbles[0] == 0xF {
+            let x = nibbles[1] as u8;
+            if nibbles[2] == 0 && nibbles[3] == 7 {
+                return Instruction::GetDelayTimer { x };
+            }
+
+            if nibbles[2] == 0 && nibbles[3] == 0xA {
+                return Instruction::WaitKey { x };
+            }
+
+            if nibbles[2] == 1 && nibbles[3] == 5 {
+                return Instruction::SetDelayTimer { x };
+            }
+
+            if nibbles[2] == 1 && nibbles[3] == 8 {
+                return Instruction::SetSoundTimer { x };
+            }
+
+            if nibbles[2] == 1 && nibbles[3] == 0xE {
+                return Instruction::AddI { x };
+            }
+
+            if nibbles[2] == 2 && nibbles[3] == 9 {
+                return Instruction::SetFontI { x };
+            }
+
+            if nibbles[2] == 3 && nibbles[3] == 3 {
+                return Instruction::BCD { x };
+            }
+
+            if nibbles[2] == 5 && nibbles[3] == 5 {
+                return Instruction::RegDump { x };
+            }
+
+            if nibbles[2] == 6 && nibbles[3] == 5 {
+                return Instruction::RegLoad { x };
+            }
+        }
+
+        Instruction::Invalid
+    }
+
+    ///
+    /// Returns the approximate cost of the instruction in COSMAC VIP machine cycles (8 clock cycles at 1.76 MHz each).
+    ///
+    /// The numbers are rough averages of published VIP interpreter timings, e.g. skips are counted as not taken.
+    /// DXYN waits for the display interrupt on the VIP, which makes it by far the most expensive instruction.
+    pub fn cycle_cost(&self) -> u32 {
+        match self {
+            Instruction::Invalid => 0,
+            Instruction::Cls => 24,
+            Instruction::Rts | Instruction::Jump { .. } | Instruction::Call { .. } | Instruction::JumpIndexed { .. } => 23,
+            Instruction::SkipEqConst { .. } | Instruction::SkipNeqConst { .. } => 12,
+            Instruction::SkipEq { .. } | Instruction::SkipNeq { .. } => 16,
+            Instruction::MovConst { .. } => 6,
+            Instruction::AddConst { .. } => 10,
+            Instruction::Mov { .. }
+            | Instruction::Or { .. }
+            | Instruction::And { .. }
+            | Instruction::Xor { .. }
+            | Instruction::Add { .. }
+            | Instruction::SubXY { .. }
+            | Instruction::RightShift { .. }
+            | Instruction::SubYX { .. }
+            | Instruction::LeftShift { .. } => 44,
+            Instruction::MovI { .. } => 12,
+            Instruction::Rand { .. } => 36,
+            Instruction::Draw { .. } => 5000,
+            Instruction::SkipKeyEq { .. } | Instruction::SkipKeyNeq { .. } => 16,
+            // WaitKey is counted per poll
+            Instruction::GetDelayTimer { .. }
+            | Instruction::WaitKey { .. }
+            | Instruction::SetDelayTimer { .. }
+            | Instruction::SetSoundTimer { .. } => 10,
+            Instruction::AddI { .. } => 19,
+            Instruction::SetFontI { .. } => 20,
+            Instruction::BCD { .. } => 204,
+            Instruction::RegDump { .. } | Instruction::RegLoad { .. } => 133,
+        }
+    }
+
+    ///
+    /// Returns the assembly mnemonic of the instruction, e.g. "ADD" or "DRW", see the Display implementation.
+    pub fn mnemonic(&self) -> &'static str {
+        match self {
+            Instruction::Invalid => "Invalid",
+            Instruction::Cls => "CLS",
+            Instruction::Rts => "RET",
+            Instruction::Jump { .. } | Instruction::JumpIndexed { .. } => "JP",
+            Instruction::Call { .. } => "CALL",
+            Instruction::SkipEqConst { .. } | Instruction::SkipEq { .. } => "SE",
+            Instruction::SkipNeqConst { .. } | Instruction::SkipNeq { .. } => "SNE",
+            Instruction::AddConst { .. } | Instruction::Add { .. } | Instruction::AddI { .. } => "ADD",
+            Instruction::Or { .. } => "OR",
+            Instruction::And { .. } => "AND",
+            Instruction::Xor { .. } => "XOR",
+            Instruction::SubXY { .. } => "SUB",
+            Instruction::RightShift { .. } => "SHR",
+            Instruction::SubYX { .. } => "SUBN",
+            Instruction::LeftShift { .. } => "SHL",
+            Instruction::Rand { .. } => "RND",
+            Instruction::Draw { .. } => "DRW",
+            Instruction::SkipKeyEq { .. } => "SKP",
+            Instruction::SkipKeyNeq { .. } => "SKNP",
+            Instruction::MovConst { .. }
+            | Instruction::Mov { .. }
+            | Instruction::MovI { .. }
+            | Instruction::GetDelayTimer { .. }
+            | Instruction::WaitKey { .. }
+            | Instruction::SetDelayTimer { .. }
+            | Instruction::SetSoundTimer { .. }
+            | Instruction::SetFontI { .. }
+            | Instruction::BCD { .. }
+            | Instruction::RegDump { .. }
+            | Instruction::RegLoad { .. } => "LD",
+        }
+    }
+
+    ///
+    /// Encodes the instruction back into its opcode, the inverse of [Instruction::decode].
+    ///
+    /// Returns None for [Instruction::Invalid] and if a field does not fit its nibbles, e.g. x > 0xF or nnn > 0xFFF.
+    pub fn encode(&self) -> Option<u16> {
+        let operands = self.operands();
+        let fits = operands.x.is_none_or(|x| x <= 0xF)
+            && operands.y.is_none_or(|y| y <= 0xF)
+            && operands.n.is_none_or(|n| n <= 0xF)
+            && operands.nnn.is_none_or(|nnn| nnn <= 0xFFF);
+        if !fits {
+            return None;
+        }
+        let op_code = match *self {
+            Instruction::Invalid => return None,
+            Instruction::Cls => 0x00E0,
+            Instruction::Rts => 0x00EE,
+            Instruction::Jump { nnn } => 0x1000 | nnn,
+            Instruction::Call { nnn } => 0x2000 | nnn,
+            Instruction::SkipEqConst { x, nn } => 0x3000 | (x as u16) << 8 | nn as u16,
+            Instruction::SkipNeqConst { x, nn } => 0x4000 | (x as u16) << 8 | nn as u16,
+            Instruction::SkipEq { x, y } => 0x5000 | (x as u16) << 8 | (y as u16) << 4,
+            Instruction::MovConst { x, nn } => 0x6000 | (x as u16) << 8 | nn as u16,
+            Instruction::AddConst { x, nn } => 0x7000 | (x as u16) << 8 | nn as u16,
+            Instruction::Mov { x, y } => 0x8000 | (x as u16) << 8 | (y as u16) << 4,
+            Instruction::Or { x, y } => 0x8001 | (x as u16) << 8 | (y as u16) << 4,
+            Instruction::And { x, y } => 0x8002 | (x as u16) << 8 | (y as u16) << 4,
+            Instruction::Xor { x, y } => 0x8003 | (x as u16) << 8 | (y as u16) << 4,
+            Instruction::Add { x, y } => 0x8004 | (x as u16) << 8 | (y as u16) << 4,
+            Instruction::SubXY { x, y } => 0x8005 | (x as u16) << 8 | (y as u16) << 4,
+            Instruction::RightShift { x, y } => 0x8006 | (x as u16) << 8 | (y as u16) << 4,
+            Instruction::SubYX { x, y } => 0x8007 | (x as u16) << 8 | (y as u16) << 4,
+            Instruction::LeftShift { x, y } => 0x800E | (x as u16) << 8 | (y as u16) << 4,
+            Instruction::SkipNeq { x, y } => 0x9000 | (x as u16) << 8 | (y as u16) << 4,
+            Instruction::MovI { nnn } => 0xA000 | nnn,
+            Instruction::JumpIndexed { nnn } => 0xB000 | nnn,
+            Instruction::Rand { x, nn } => 0xC000 | (x as u16) << 8 | nn as u16,
+            Instruction::Draw { x, y, n } => 0xD000 | (x as u16) << 8 | (y as u16) << 4 | n as u16,
+            Instruction::SkipKeyEq { x } => 0xE09E | (x as u16) << 8,
+            Instruction::SkipKeyNeq { x } => 0xE0A1 | (x as u16) << 8,
+            Instruction::GetDelayTimer { x } => 0xF007 | (x as u16) << 8,
+            Instruction::WaitKey { x } => 0xF00A | (x as u16) << 8,
+            Instruction::SetDelayTimer { x } => 0xF015 | (x as u16) << 8,
+            Instruction::SetSoundTimer { x } => 0xF018 | (x as u16) << 8,
+            Instruction::AddI { x } => 0xF01E | (x as u16) << 8,
+            Instruction::SetFontI { x } => 0xF029 | (x as u16) << 8,
+            Instruction::BCD { x } => 0xF033 | (x as u16) << 8,
+            Instruction::RegDump { x } => 0xF055 | (x as u16) << 8,
+            Instruction::RegLoad { x } => 0xF065 | (x as u16) << 8,
+        };
+        Some(op_code)
+    }
+
+    /// Creates 00E0, [Instruction::Cls].
+    pub fn cls() -> Self {
+        Instruction::Cls
+    }
+
+    /// Creates 00EE, [Instruction::Rts].
+    pub fn rts() -> Self {
+        Instruction::Rts
+    }
+
+    /// Creates 1NNN, [Instruction::Jump].
+    pub fn jump(nnn: u16) -> Self {
+        Instruction::Jump { nnn }
+    }
+
+    /// Creates 2NNN, [Instruction::Call].
+    pub fn call(nnn: u16) -> Self {
+        Instruction::Call { nnn }
+    }
+
+    /// Creates 3XNN, [Instruction::SkipEqConst].
+    pub fn skip_eq_const(x: u8, nn: u8) -> Self {
+        Instruction::SkipEqConst { x, nn }
+    }
+
+    /// Creates 4XNN, [Instruction::SkipNeqConst].
+    pub fn skip_neq_const(x: u8, nn: u8) -> Self {
+        Instruction::SkipNeqConst { x, nn }
+    }
+
+    /// Creates 5XY0, [Instruction::SkipEq].
+    pub fn skip_eq(x: u8, y: u8) -> Self {
+        Instruction::SkipEq { x, y }
+    }
+
+    /// Creates 6XNN, [Instruction::MovConst].
+    pub fn mov_const(x: u8, nn: u8) -> Self {
+        Instruction::MovConst { x, nn }
+    }
+
+    /// Creates 7XNN, [Instruction::AddConst].
+    pub fn add_const(x: u8, nn: u8) -> Self {
+        Instruction::AddConst { x, nn }
+    }
+
+    /// Creates 8XY0, [Instruction::Mov].
+    pub fn mov(x: u8, y: u8) -> Self {
+        Instruction::Mov { x, y }
+    }
+
+    /// Creates 8XY1, [Instruction::Or].
+    pub fn or(x: u8, y: u8) -> Self {
+        Instruction::Or { x, y }
+    }
+
+    /// Creates 8XY2, [Instruction::And].
+    pub fn and(x: u8, y: u8) -> Self {
+        Instruction::And { x, y }
+    }
+
+    /// Creates 8XY3, [Instruction::Xor].
+    pub fn xor(x: u8, y: u8) -> Self {
+        Instruction::Xor { x, y }
+    }
+
+    /// Creates 8XY4, [Instruction::Add].
+    pub fn add(x: u8, y: u8) -> Self {
+        Instruction::Add { x, y }
+    }
+
+    /// Creates 8XY5, [Instruction::SubXY].
+    pub fn sub_xy(x: u8, y: u8) -> Self {
+        Instruction::SubXY { x, y }
+    }
+
+    /// Creates 8XY6, [Instruction::RightShift].
+    pub fn right_shift(x: u8, y: u8) -> Self {
+        Instruction::RightShift { x, y }
+    }
+
+    /// Creates 8XY7, [Instruction::SubYX].
+    pub fn sub_yx(x: u8, y: u8) -> Self {
+        Instruction::SubYX { x, y }
+    }
+
+    /// Creates 8XYE, [Instruction::LeftShift].
+    pub fn left_shift(x: u8, y: u8) -> Self {
+        Instruction::LeftShift { x, y }
+    }
+
+    /// Creates 9XY0, [Instruction::SkipNeq].
+    pub fn skip_neq(x: u8, y: u8) -> Self {
+        Instruction::SkipNeq { x, y }
+    }
+
+    /// Creates ANNN, [Instruction::MovI].
+    pub fn mov_i(nnn: u16) -> Self {
+        Instruction::MovI { nnn }
+    }
+
+    /// Creates BNNN, [Instruction::JumpIndexed].
+    pub fn jump_indexed(nnn: u16) -> Self {
+        Instruction::JumpIndexed { nnn }
+    }
+
+    /// Creates CXNN, [Instruction::Rand].
+    pub fn rand(x: u8, nn: u8) -> Self {
+        Instruction::Rand { x, nn }
+    }
+
+    /// Creates DXYN, [Instruction::Draw].
+    pub fn draw(x: u8, y: u8, n: u8) -> Self {
+        Instruction::Draw { x, y, n }
+    }
+
+    /// Creates EX9E, [Instruction::SkipKeyEq].
+    pub fn skip_key_eq(x: u8) -> Self {
+        Instruction::SkipKeyEq { x }
+    }
+
+    /// Creates EXA1, [Instruction::SkipKeyNeq].
+    pub fn skip_key_neq(x: u8) -> Self {
+        Instruction::SkipKeyNeq { x }
+    }
+
+    /// Creates FX07, [Instruction::GetDelayTimer].
+    pub fn get_delay_timer(x: u8) -> Self {
+        Instruction::GetDelayTimer { x }
+    }
+
+    /// Creates FX0A, [Instruction::WaitKey].
+    pub fn wait_key(x: u8) -> Self {
+        Instruction::WaitKey { x }
+    }
+
+    /// Creates FX15, [Instruction::SetDelayTimer].
+    pub fn set_delay_timer(x: u8) -> Self {
+        Instruction::SetDelayTimer { x }
+    }
+
+    /// Creates FX18, [Instruction::SetSoundTimer].
+    pub fn set_sound_timer(x: u8) -> Self {
+        Instruction::SetSoundTimer { x }
+    }
+
+    /// Creates FX1E, [Instruction::AddI].
+    pub fn add_i(x: u8) -> Self {
+        Instruction::AddI { x }
+    }
+
+    /// Creates FX29, [Instruction::SetFontI].
+    pub fn set_font_i(x: u8) -> Self {
+        Instruction::SetFontI { x }
+    }
+
+    /// Creates FX33, [Instruction::BCD].
+    pub fn bcd(x: u8) -> Self {
+        Instruction::BCD { x }
+    }
+
+    /// Creates FX55, [Instruction::RegDump].
+    pub fn reg_dump(x: u8) -> Self {
+        Instruction::RegDump { x }
+    }
+
+    /// Creates FX65, [Instruction::RegLoad].
+    pub fn reg_load(x: u8) -> Self {
+        Instruction::RegLoad { x }
+    }
+
+    ///
+    /// Returns the fields of the instruction without matching on the variant, e.g. for generic formatters and analyzers.
+    /// Fields the instruction does not have are None.
+    pub fn operands(&self) -> Operands {
+        let mut operands = Operands::default();
+        match *self {
+            Instruction::Invalid | Instruction::Cls | Instruction::Rts => (),
+            Instruction::Jump { nnn } | Instruction::Call { nnn } | Instruction::MovI { nnn } | Instruction::JumpIndexed { nnn } => {
+                operands.nnn = Some(nnn)
+            },
+            Instruction::SkipEqConst { x, nn }
+            | Instruction::SkipNeqConst { x, nn }
+            | Instruction::MovConst { x, nn }
+            | Instruction::AddConst { x, nn }
+            | Instruction::Rand { x, nn } => {
+                operands.x = Some(x);
+                operands.nn = Some(nn);
+            },
+            Instruction::SkipEq { x, y }
+            | Instruction::Mov { x, y }
+            | Instruction::Or { x, y }
+            | Instruction::And { x, y }
+            | Instruction::Xor { x, y }
+            | Instruction::Add { x, y }
+            | Instruction::SubXY { x, y }
+            | Instruction::RightShift { x, y }
+            | Instruction::SubYX { x, y }
+            | Instruction::LeftShift { x, y }
+            | Instruction::SkipNeq { x, y } => {
+                operands.x = Some(x);
+                operands.y = Some(y);
+            },
+            Instruction::Draw { x, y, n } => {
+                operands.x = Some(x);
+                operands.y = Some(y);
+                operands.n = Some(n);
+            },
+            Instruction::SkipKeyEq { x }
+            | Instruction::SkipKeyNeq { x }
+            | Instruction::GetDelayTimer { x }
+            | Instruction::WaitKey { x }
+            | Instruction::SetDelayTimer { x }
+            | Instruction::SetSoundTimer { x }
+            | Instruction::AddI { x }
+            | Instruction::SetFontI { x }
+            | Instruction::BCD { x }
+            | Instruction::RegDump { x }
+            | Instruction::RegLoad { x } => operands.x = Some(x),
+        }
+        operands
+    }
+
+    // true if executing the instruction writes VF, either as flag or as destination register
+    fn writes_vf(&self) -> bool {
+        match *self {
+            Instruction::Add { .. }
+            | Instruction::SubXY { .. }
+            | Instruction::RightShift { .. }
+            | Instruction::SubYX { .. }
+            | Instruction::LeftShift { .. }
+            | Instruction::Draw { .. } => true,
+            Instruction::MovConst { x, .. }
+            | Instruction::AddConst { x, .. }
+            | Instruction::Mov { x, .. }
+            | Instruction::Or { x, .. }
+            | Instruction::And { x, .. }
+            | Instruction::Xor { x, .. }
+            | Instruction::Rand { x, .. }
+            | Instruction::GetDelayTimer { x }
+            | Instruction::WaitKey { x }
+            | Instruction::RegLoad { x } => x == 0xF,
+            _ => false,
+        }
+    }
+
+    ///
+    /// Returns what executing the instruction touches, e.g. for a debugger to highlight it before stepping.
+    /// The registers depend on the quirks, e.g. shifts read VY instead of VX with [Quirks::shift_uses_vy].
+    /// Flag writes to VF are included.
+    ///
+    /// # Arguments
+    /// * 'quirks' - quirks the instruction would be executed with
+    pub fn effects(&self, quirks: &Quirks) -> InstructionEffects {
+        InstructionEffects {
+            registers_read: self.registers_read(quirks),
+            registers_written: self.registers_written(quirks),
+            reads_memory: matches!(self, Instruction::Draw { .. } | Instruction::RegLoad { .. }),
+            writes_memory: matches!(self, Instruction::BCD { .. } | Instruction::RegDump { .. }),
+            display: matches!(self, Instruction::Cls | Instruction::Draw { .. }),
+            keypad: matches!(self, Instruction::SkipKeyEq { .. } | Instruction::SkipKeyNeq { .. } | Instruction::WaitKey { .. }),
+            timers: matches!(
+                self,
+                Instruction::GetDelayTimer { .. } | Instruction::SetDelayTimer { .. } | Instruction::SetSoundTimer { .. }
+            ),
+        }
+    }
+
+    ///
+    /// Returns true if the instruction can continue anywhere but at the next instruction: jumps, calls, returns and skips.
+    pub fn is_branch(&self) -> bool {
+        matches!(
+            self,
+            Instruction::Jump { .. }
+                | Instruction::Call { .. }
+                | Instruction::Rts
+                | Instruction::JumpIndexed { .. }
+                | Instruction::SkipEqConst { .. }
+                | Instruction::SkipNeqConst { .. }
+                | Instruction::SkipEq { .. }
+                | Instruction::SkipNeq { .. }
+                | Instruction::SkipKeyEq { .. }
+                | Instruction::SkipKeyNeq { .. }
+        )
+    }
+
+    ///
+    /// Returns the addresses execution can continue at after a branch, e.g. for a basic-block analyzer.
+    /// Skips return the next instruction and the one after it, a call returns its target. Returns are not known
+    /// statically and, like non-branches, return an empty Vec.
+    ///
+    /// A skip is assumed to step over a 2 byte instruction. With [Quirks::skip_long_instructions] a skip over the
+    /// 4 byte F000 NNNN continues at pc + 6 instead of pc + 4, which this does not see without the memory.
+    ///
+    /// # Arguments
+    /// * 'pc' - address of the instruction
+    /// * 'v0' - value of V0, the offset of JumpIndexed
+    pub fn branch_targets(&self, pc: usize, v0: u8) -> Vec<usize> {
+        match *self {
+            Instruction::Jump { nnn } | Instruction::Call { nnn } => vec![nnn as usize],
+            Instruction::JumpIndexed { nnn } => vec![nnn as usize + v0 as usize],
+            Instruction::SkipEqConst { .. }
+            | Instruction::SkipNeqConst { .. }
+            | Instruction::SkipEq { .. }
+            | Instruction::SkipNeq { .. }
+            | Instruction::SkipKeyEq { .. }
+            | Instruction::SkipKeyNeq { .. } => vec![pc + 2, pc + 4],
+            _ => Vec::new(),
+        }
+    }
+
+    ///
+    /// Returns the family of the instruction, see [InstructionSet]. Invalid belongs to no family.
+    pub fn family(&self) -> InstructionSet {
+        match self {
+            Instruction::Invalid => InstructionSet::EMPTY,
+            Instruction::Cls | Instruction::Draw { .. } => InstructionSet::DISPLAY,
+            Instruction::Jump { .. } | Instruction::Call { .. } | Instruction::Rts | Instruction::JumpIndexed { .. } => {
+                InstructionSet::FLOW
+            },
+            Instruction::SkipEqConst { .. }
+            | Instruction::SkipNeqConst { .. }
+            | Instruction::SkipEq { .. }
+            | Instruction::SkipNeq { .. } => InstructionSet::SKIP,
+            Instruction::MovConst { .. } | Instruction::AddConst { .. } => InstructionSet::CONST,
+            Instruction::Mov { .. }
+            | Instruction::Or { .. }
+            | Instruction::And { .. }
+            | Instruction::Xor { .. }
+            | Instruction::Add { .. }
+            | Instruction::SubXY { .. }
+            | Instruction::RightShift { .. }
+            | Instruction::SubYX { .. }
+            | Instruction::LeftShift { .. } => InstructionSet::ALU,
+            Instruction::MovI { .. } | Instruction::AddI { .. } | Instruction::SetFontI { .. } => InstructionSet::INDEX,
+            Instruction::Rand { .. } => InstructionSet::RAND,
+            Instruction::SkipKeyEq { .. } | Instruction::SkipKeyNeq { .. } | Instruction::WaitKey { .. } => {
+                InstructionSet::KEYPAD
+            },
+            Instruction::GetDelayTimer { .. } | Instruction::SetDelayTimer { .. } | Instruction::SetSoundTimer { .. } => {
+                InstructionSet::TIMERS
+            },
+            Instruction::BCD { .. } | Instruction::RegDump { .. } | Instruction::RegLoad { .. } => InstructionSet::MEMORY,
+        }
+    }
+
+    // bitmask of the general purpose registers the instruction reads under quirks, bit i is Vi
+    fn registers_read(&self, quirks: &Quirks) -> u16 {
+        match *self {
+            Instruction::RightShift { x, y } | Instruction::LeftShift { x, y } => {
+                if quirks.shift_uses_vy {
+                    1 << y
+                } else {
+                    1 << x
+                }
+            },
+            Instruction::SkipEqConst { x, .. }
+            | Instruction::SkipNeqConst { x, .. }
+            | Instruction::AddConst { x, .. }
+            | Instruction::SkipKeyEq { x }
+            | Instruction::SkipKeyNeq { x }
+            | Instruction::SetDelayTimer { x }
+            | Instruction::SetSoundTimer { x }
+            | Instruction::AddI { x }
+            | Instruction::SetFontI { x }
+            | Instruction::BCD { x } => 1 << x,
+            Instruction::Mov { y, .. } => 1 << y,
+            Instruction::SkipEq { x, y }
+            | Instruction::SkipNeq { x, y }
+            | Instruction::Or { x, y }
+            | Instruction::And { x, y }
+            | Instruction::Xor { x, y }
+            | Instruction::Add { x, y }
+            | Instruction::SubXY { x, y }
+            | Instruction::SubYX { x, y }
+            | Instruction::Draw { x, y, .. } => 1 << x | 1 << y,
+            Instruction::JumpIndexed { .. } => 1,
+            Instruction::RegDump { x } => Instruction::registers_up_to(x),
+            _ => 0,
+        }
+    }
+
+    // bitmask of the general purpose registers the instruction writes under quirks, bit i is Vi
+    fn registers_written(&self, quirks: &Quirks) -> u16 {
+        match *self {
+            Instruction::MovConst { x, .. }
+            | Instruction::AddConst { x, .. }
+            | Instruction::Mov { x, .. }
+            | Instruction::Or { x, .. }
+            | Instruction::And { x, .. }
+            | Instruction::Xor { x, .. }
+            | Instruction::Rand { x, .. }
+            | Instruction::GetDelayTimer { x }
+            | Instruction::WaitKey { x } => 1 << x,
+            Instruction::Add { x, .. }
+            | Instruction::SubXY { x, .. }
+            | Instruction::RightShift { x, .. }
+            | Instruction::SubYX { x, .. }
+            | Instruction::LeftShift { x, .. } => 1 << x | 1 << 0xF,
+            Instruction::Draw { .. } => 1 << 0xF,
+            Instruction::AddI { .. } if quirks.add_i_sets_overflow => 1 << 0xF,
+            Instruction::RegLoad { x } => Instruction::registers_up_to(x),
+            _ => 0,
+        }
+    }
+
+    // bitmask of V0..=Vx
+    fn registers_up_to(x: u8) -> u16 {
+        ((1u32 << (x as u32 + 1)) - 1) as u16
+    }
+
+    ///
+    /// Like [Instruction::decode], but explains why an opcode is invalid.
+    pub fn decode_detailed(op_code: u16) -> Result<Instruction, DecodeError> {
+        let instruction = Instruction::decode(op_code);
+        if instruction != Instruction::Invalid {
+            return Ok(instruction);
+        }
+
+        // every first nibble is used, so an invalid opcode always has a valid family
+        let family = (op_code >> 12) as u8;
+        match family {
+            0x0 => Err(DecodeError::MachineCodeCall { nnn: op_code & 0x0FFF }),
+            0x5 | 0x8 | 0x9 => Err(DecodeError::BadLowNibble { family }),
+            _ => Err(DecodeError::ReservedPattern { family }),
+        }
+    }
+
+    fn code_to_nibble_array(op_code: u16) -> [u16; 4] {
+        [
+            (op_code & 0xF000) >> 12,
+            (op_code & 0x0F00) >> 8,
+            (op_code & 0x00F0) >> 4,
+            op_code & 0x000F,
+        ]
+    }
+
+    // combines up to 4 nibbles (most significant first) into one value, e.g. the 3 lower nibbles to NNN
+    fn combine_nibbles(nibbles: &[u16]) -> u16 {
+        // a u16 only holds 4 nibbles, more would shift out of range
+        debug_assert!(nibbles.len() <= 4, "cannot combine {} nibbles into a u16", nibbles.len());
+        let mut combined = 0;
+        for nibble in nibbles.iter().take(4) {
+            combined = (combined << 4) | (*nibble & 0x000F);
+        }
+        combined
+    }
+}
+
+// Assembly syntax follows Cowgod's technical reference: http://devernay.free.fr/hacks/chip8/C8TECH10.HTM
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Instruction::Invalid => write!(f, "Invalid"),
+            Instruction::Cls => write!(f, "CLS"),
+            Instruction::Rts => write!(f, "RET"),
+            Instruction::Jump { nnn } => write!(f, "JP {:#05x}", nnn),
+            Instruction::Call { nnn } => write!(f, "CALL {:#05x}", nnn),
+            Instruction::SkipEqConst { x, nn } => write!(f, "SE V{:X}, {:#04x}", x, nn),
+            Instruction::SkipNeqConst { x, nn } => write!(f, "SNE V{:X}, {:#04x}", x, nn),
+            Instruction::SkipEq { x, y } => write!(f, "SE V{:X}, V{:X}", x, y),
+            Instruction::MovConst { x, nn } => write!(f, "LD V{:X}, {:#04x}", x, nn),
+            Instruction::AddConst { x, nn } => write!(f, "ADD V{:X}, {:#04x}", x, nn),
+            Instruction::Mov { x, y } => write!(f, "LD V{:X}, V{:X}", x, y),
+            Instruction::Or { x, y } => write!(f, "OR V{:X}, V{:X}", x, y),
+            Instruction::And { x, y } => write!(f, "AND V{:X}, V{:X}", x, y),
+            Instruction::Xor { x, y } => write!(f, "XOR V{:X}, V{:X}", x, y),
+            Instruction::Add { x, y } => write!(f, "ADD V{:X}, V{:X}", x, y),
+            Instruction::SubXY { x, y } => write!(f, "SUB V{:X}, V{:X}", x, y),
+            Instruction::RightShift { x, y } => write!(f, "SHR V{:X}, V{:X}", x, y),
+            Instruction::SubYX { x, y } => write!(f, "SUBN V{:X}, V{:X}", x, y),
+            Instruction::LeftShift { x, y } => write!(f, "SHL V{:X}, V{:X}", x, y),
+            Instruction::SkipNeq { x, y } => write!(f, "SNE V{:X}, V{:X}", x, y),
+            Instruction::MovI { nnn } => write!(f, "LD I, {:#05x}", nnn),
+            Instruction::JumpIndexed { nnn } => write!(f, "JP V0, {:#05x}", nnn),
+            Instruction::Rand { x, nn } => write!(f, "RND V{:X}, {:#04x}", x, nn),
+            Instruction::Draw { x, y, n } => write!(f, "DRW V{:X}, V{:X}, {}", x, y, n),
+            Instruction::SkipKeyEq { x } => write!(f, "SKP V{:X}", x),
+            Instruction::SkipKeyNeq { x } => write!(f, "SKNP V{:X}", x),
+            Instruction::GetDelayTimer { x } => write!(f, "LD V{:X}, DT", x),
+            Instruction::WaitKey { x } => write!(f, "LD V{:X}, K", x),
+            Instruction::SetDelayTimer { x } => write!(f, "LD DT, V{:X}", x),
+            Instruction::SetSoundTimer { x } => write!(f, "LD ST, V{:X}", x),
+            Instruction::AddI { x } => write!(f, "ADD I, V{:X}", x),
+            Instruction::SetFontI { x } => write!(f, "LD F, V{:X}", x),
+            Instruction::BCD { x } => write!(f, "LD B, V{:X}", x),
+            Instruction::RegDump { x } => write!(f, "LD [I], V{:X}", x),
+            Instruction::RegLoad { x } => write!(f, "LD V{:X}, [I]", x),
+        }
+    }
+}
+
+/// A problem found by [validate_rom].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RomWarning {
+    /// The ROM does not fit into the 4KiB memory behind 0x200
+    TooLarge { size: usize, max: usize },
+    /// The ROM has an odd number of bytes, the last instruction is incomplete
+    OddLength,
+    /// The word at address does not decode. Sprite data in a ROM decodes like this as well, so a few are normal
+    InvalidOpcode { address: usize, op_code: u16 },
+    /// Not a single word decodes to an instruction, it's likely not a chip8 ROM at all
+    NoInstructions,
+}
+
+///
+/// Checks a ROM before loading it, so front-ends can warn about garbage. The ROM is decoded linearly from 0x200.
+///
+/// Returns the warnings in the order size, length, invalid opcodes (by address), no instructions. Empty if the ROM
+/// looks fine.
+pub fn validate_rom(rom: &[u8]) -> Vec<RomWarning> {
+    let mut warnings = Vec::new();
+    let max = MEM_SIZE - PROGRAM_START;
+    if rom.len() > max {
+        warnings.push(RomWarning::TooLarge { size: rom.len(), max });
+    }
+    if !rom.len().is_multiple_of(2) {
+        warnings.push(RomWarning::OddLength);
+    }
+    let mut any_valid = false;
+    for (address, op_code, instruction) in disassemble(rom, PROGRAM_START) {
+        if instruction == Instruction::Invalid {
+            warnings.push(RomWarning::InvalidOpcode { address, op_code });
+        } else {
+            any_valid = true;
+        }
+    }
+    if !any_valid {
+        warnings.push(RomWarning::NoInstructions);
+    }
+    warnings
+}
+
+///
+/// Returns a program that only consists of `JP 0x200`, so a [State] initialized with it spins forever.
+/// Useful to test peripheral setups without a real ROM.
+pub fn halt_program() -> Vec<u8> {
+    vec![0x12, 0x00]
+}
+
+///
+/// Decodes every 2-byte word of rom. Data in the rom is decoded as well, usually to [Instruction::Invalid].
+///
+/// Returns (address, opcode, instruction) for every word. A trailing odd byte is padded with 0x00.
+///
+/// # Arguments
+/// * 'rom' - the program bytes
+/// * 'base' - address of the first byte, usually 0x200
+pub fn disassemble(rom: &[u8], base: usize) -> Vec<(usize, u16, Instruction)> {
+    rom.chunks(2)
+        .enumerate()
+        .map(|(i, word)| {
+            let op_code = (word[0] as u16) << 8 | *word.get(1).unwrap_or(&0) as u16;
+            (base + 2 * i, op_code, Instruction::decode(op_code))
+        })
+        .collect()
+}
+
+///
+/// Formats the output of [disassemble] as a listing with one line per entry.
+/// Words that do not decode to an instruction are emitted as data, e.g. `DB 0xffff`.
+pub fn format_listing(entries: &[(usize, u16, Instruction)]) -> String {
+    let mut listing = String::new();
+    for (addr, op_code, instruction) in entries {
+        let line = match instruction {
+            Instruction::Invalid => format!("{:#05x}: {:#06x}  DB {:#06x}\n", addr, op_code, op_code),
+            _ => format!("{:#05x}: {:#06x}  {}\n", addr, op_code, instruction),
+        };
+        listing.push_str(&line);
+    }
+    listing
+}
+
+///
+/// Disassembles rom into a map from address to the formatted instruction, e.g. for source-mapped debugging.
+/// The instructions are formatted like in [format_listing], without address and opcode.
+///
+/// # Arguments
+/// * 'rom' - the program bytes
+/// * 'base' - address of the first byte, usually 0x200
+pub fn disassemble_map(rom: &[u8], base: usize) -> BTreeMap<usize, String> {
+    disassemble(rom, base)
+        .into_iter()
+        .map(|(addr, op_code, instruction)| {
+            let line = match instruction {
+                Instruction::Invalid => format!("DB {:#06x}", op_code),
+                _ => format!("{}", instruction),
+            };
+            (addr, line)
+        })
+        .collect()
+}
+
+///
+/// Finds the sprites a ROM draws by looking for MovI followed by DXYN, e.g. to extract the sprites of a ROM.
+/// The ROM is decoded linearly: instructions between MovI and the draw are fine, but instructions that change I
+/// (or may, like RegDump) and jumps, calls and returns end the search for the draws of a MovI.
+///
+/// Returns (sprite address, N) sorted by address without duplicates. N is 0 for DXY0, whose size depends on the mode.
+///
+/// # Arguments
+/// * 'rom' - the program bytes
+/// * 'base' - address of the first byte, usually 0x200
+pub fn find_sprite_references(rom: &[u8], base: usize) -> Vec<(usize, u8)> {
+    let mut references = Vec::new();
+    let mut index = None;
+    for (_, _, instruction) in disassemble(rom, base) {
+        match instruction {
+            Instruction::MovI { nnn } => index = Some(nnn as usize),
+            Instruction::Draw { n, .. } => {
+                if let Some(address) = index {
+                    references.push((address, n));
+                }
+            },
+            Instruction::AddI { .. }
+            | Instruction::SetFontI { .. }
+            | Instruction::RegDump { .. }
+            | Instruction::RegLoad { .. }
+            | Instruction::Jump { .. }
+            | Instruction::JumpIndexed { .. }
+            | Instruction::Call { .. }
+            | Instruction::Rts => index = None,
+            _ => (),
+        }
+    }
+    references.sort_unstable();
+    references.dedup();
+    references
+}
+
+// Core with owned peripherals needs neither std nor locks, these tests also run with --no-default-features
+#[cfg(test)]
+mod core_tests {
+    use super::*;
+
+    #[test]
+    fn owned_core_test() {
+        let mut core = Core::new(
+            DisplayBuffer::new(),
+            DebugTimer { value: 0 },
+            DebugBeeper { value: 0 },
+            DebugKeypad { currently_pressed: None },
+        );
+        // draw the font "0", DT = 5, wait for a key
+        core.initialize(&[0xA0, 0x50, 0xD0, 0x05, 0x60, 0x05, 0xF0, 0x15, 0xF1, 0x0A], &DEFAULT_FONT);
+        for _ in 0..4 {
+            core.execute().unwrap();
+        }
+        assert!(core.display.get_pixel(0, 0) && !core.display.get_pixel(1, 1));
+        assert_eq!(core.delay_timer.get(), 5);
+
+        core.execute().unwrap();
+        assert_eq!(core.pc, 0x208);
+        core.keypad.currently_pressed = Some(0xB);
+        core.execute().unwrap();
+        assert_eq!(core.gp_registers[1], 0xB);
+    }
+
+    #[test]
+    fn owned_buffers_test() {
+        let mut core = Core::new(DisplayBuffer::new(), CountdownTimer::new(), CountdownBeeper::new(), KeypadState::new());
+        // draw the font "0", ST = 5, wait for a key
+        core.initialize(&[0xA0, 0x50, 0xD0, 0x05, 0x60, 0x05, 0xF0, 0x18, 0xF1, 0x0A], &DEFAULT_FONT);
+        for _ in 0..4 {
+            core.execute().unwrap();
+        }
+        assert_eq!(core.display().lit_pixel_count(), 14);
+        assert!(core.should_beep());
+        for _ in 0..5 {
+            core.tick_timers();
+        }
+        assert!(!core.should_beep());
+
+        core.execute().unwrap();
+        assert_eq!(core.pc, 0x208);
+        core.keypad_mut().press(0xB);
+        core.execute().unwrap();
+        assert_eq!(core.gp_registers[1], 0xB);
+    }
+
+    #[test]
+    fn owned_rng_seed_test() {
+        let new = || Core::new(NullDisplay, NullTimer, NullBeeper, NullKeypad).with_rng_seed(42);
+        // V0 = rand & 0xFF, V1 = rand & 0xFF
+        let rom = [0xC0, 0xFF, 0xC1, 0xFF];
+        let (mut a, mut b) = (new(), new());
+        a.initialize(&rom, &DEFAULT_FONT);
+        b.initialize(&rom, &DEFAULT_FONT);
+        for _ in 0..2 {
+            a.execute().unwrap();
+            b.execute().unwrap();
+        }
+        assert_eq!(a.gp_registers, b.gp_registers);
+    }
+
+    #[test]
+    fn linear_memory_test() {
+        let mut memory = LinearMemory::new(16);
+        memory.write(15, 0xAB);
+        assert_eq!(memory.read(15), 0xAB);
+        memory.resize(32, 0xFF);
+        assert_eq!(memory.size(), 32);
+        assert_eq!(memory.read(16), 0xFF);
+        assert_eq!(&memory.as_slice().unwrap()[14..17], &[0, 0xAB, 0xFF]);
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_works() {
+        let result = add(2, 2);
+        assert_eq!(result, 4);
+    }
+
+    #[test]
+    fn u8_to_bool_test() {
+        let byte: u8 = 0b10110011;
+        let array = u8_to_bool_array(byte);
+        assert_eq!(array, [true, false, true, true, false, false, true, true]);
+        let byte: u8 = 0b00000000;
+        let array = u8_to_bool_array(byte);
+        assert_eq!(
+            array,
+            [false, false, false, false, false, false, false, false]
+        );
+        let byte: u8 = 0b11111111;
+        let array = u8_to_bool_array(byte);
+        assert_eq!(array, [true, true, true, true, true, true, true, true]);
+    }
+
+    fn debug_state() -> State {
+        debug_state_with(State::new)
+    }
+
+    fn debug_state_with(new: fn(SharedDisplay, SharedTimer, SharedBeeper, SharedKeypad) -> State) -> State {
+        new(
+            Arc::new(Mutex::new(DebugDisplay {
+                ret: false,
+                width: 64,
+                height: 32,
+            })),
+            Arc::new(Mutex::new(DebugTimer { value: 0 })),
+            Arc::new(Mutex::new(DebugBeeper { value: 0 })),
+            Arc::new(Mutex::new(DebugKeypad {
+                currently_pressed: None,
+            })),
+        )
+    }
+
+    #[test]
+    fn preloaded_delay_timer_test() {
+        let mut state = debug_state().with_timers(5, 0);
+        // FX07 with x = 0
+        state.initialize(&[0xF0, 0x07], &DEFAULT_FONT);
+        state.execute().unwrap();
+        assert_eq!(state.gp_registers[0], 5);
+    }
+
+    #[test]
+    fn get_pixel_test() {
+        let mut buffer = DisplayBuffer::new();
+        buffer.modify(&[0b10000001], 1, 3, 2);
+        let display: &dyn Display = &buffer;
+        assert!(display.get_pixel(3, 2));
+        assert!(!display.get_pixel(4, 2));
+        assert!(display.get_pixel(10, 2));
+        // out of range reads are just off
+        assert!(!display.get_pixel(64, 2));
+
+        let debug_display = DebugDisplay {
+            ret: true,
+            width: 64,
+            height: 32,
+        };
+        let display: &dyn Display = &debug_display;
+        assert!(!display.get_pixel(3, 2));
+    }
+
+    #[test]
+    fn aligned_pc_test() {
+        // jump to 0x203, which happens to decode as 6507 (V5 = 0x07)
+        let program = [0x12, 0x03, 0x00, 0x65, 0x07];
+
+        let mut state = debug_state();
+        state.initialize(&program, &DEFAULT_FONT);
+        state.execute().unwrap();
+        state.execute().unwrap();
+        assert_eq!(state.gp_registers[5], 0x07);
+        assert_eq!(state.pc, 0x205);
+
+        let mut state = debug_state();
+        state.set_require_aligned_pc(true);
+        state.initialize(&program, &DEFAULT_FONT);
+        state.execute().unwrap();
+        assert_eq!(state.execute(), Err(ExecutionError::MisalignedPc(0x203)));
+        assert_eq!(state.gp_registers[5], 0);
+        assert_eq!(state.pc, 0x203);
+    }
+
+    #[test]
+    fn modify_many_test() {
+        let first = [0xFF, 0x81];
+        let second = [0x18];
+        let third = [0xF0, 0x0F, 0xF0];
+        let batch: [(&[u8], u8, u8, u8); 3] = [(&first, 2, 4, 4), (&second, 1, 0, 5), (&third, 3, 60, 30)];
+
+        let mut sequential = DisplayBuffer::new();
+        let mut expected = false;
+        for (sprite, n, x, y) in batch {
+            expected |= sequential.modify(sprite, n, x, y);
+        }
+
+        let mut batched = DisplayBuffer::new();
+        assert_eq!(batched.modify_many(&batch), expected);
+        assert!(expected);
+        assert_eq!(batched.display, sequential.display);
+    }
+
+    #[test]
+    fn cpu_state_eq_test() {
+        // V0 = 0x10, V1 = 0x22, V0 += V1, I = 0x300
+        let program = [0x60, 0x10, 0x61, 0x22, 0x80, 0x14, 0xA3, 0x00];
+
+        let mut a = debug_state();
+        let mut b = debug_state();
+        a.initialize(&program, &DEFAULT_FONT);
+        b.initialize(&program, &DEFAULT_FONT);
+        for _ in 0..4 {
+            a.execute().unwrap();
+            b.execute().unwrap();
+        }
+        assert!(a.cpu_state_eq(&b));
+        assert_eq!(a.diff(&b), None);
+
+        b.gp_registers[1] = 0x23;
+        assert!(!a.cpu_state_eq(&b));
+        assert_eq!(a.diff(&b), Some(String::from("V1: 0x22 != 0x23")));
+    }
+
+    #[test]
+    fn last_draw_bounds_test() {
+        let mut buffer = DisplayBuffer::new();
+        assert_eq!(buffer.last_draw_bounds(), None);
+
+        buffer.modify(&[0xFF, 0x81, 0xFF], 3, 10, 5);
+        assert_eq!(buffer.last_draw_bounds(), Some((10, 5, 17, 7)));
+
+        // only the set bits count, clipped pixels are not part of the bounds
+        buffer.modify(&[0x30, 0x00, 0x01], 3, 60, 30);
+        assert_eq!(buffer.last_draw_bounds(), Some((62, 30, 63, 30)));
+
+        buffer.modify(&[0x00], 1, 0, 0);
+        assert_eq!(buffer.last_draw_bounds(), None);
+    }
+
+    #[test]
+    fn format_listing_test() {
+        // LD V0, 5; JP 0x200; then two words of sprite data
+        let rom = [0x60, 0x05, 0x12, 0x00, 0xFF, 0xFF, 0xF0, 0x00];
+        let entries = disassemble(&rom, PROGRAM_START);
+        assert_eq!(entries.len(), 4);
+        assert_eq!(entries[0], (0x200, 0x6005, Instruction::MovConst { x: 0, nn: 5 }));
+        assert_eq!(entries[2], (0x204, 0xFFFF, Instruction::Invalid));
+
+        let listing = format_listing(&entries);
+        let lines: Vec<&str> = listing.lines().collect();
+        assert_eq!(
+            lines,
+            [
+                "0x200: 0x6005  LD V0, 0x05",
+                "0x202: 0x1200  JP 0x200",
+                "0x204: 0xffff  DB 0xffff",
+                "0x206: 0xf000  DB 0xf000",
+            ]
+        );
+    }
+
+    // runs steps instructions and asserts that the executed (pc, opcode) pairs equal expected
+    fn assert_trace_matches(state: &mut State, expected: &[(usize, u16)], steps: usize) {
+        let mut trace = Vec::with_capacity(steps);
+        for _ in 0..steps {
+            let op_code = (state.memory()[state.pc] as u16) << 8 | state.memory()[state.pc + 1] as u16;
+            trace.push((state.pc, op_code));
+            state.execute().unwrap();
+        }
+        assert_eq!(trace, expected);
+    }
+
+    fn buffer_state() -> (State, SharedPeripherals) {
+        State::from_buffers(DisplayBuffer::new(), CountdownTimer::new(), CountdownBeeper::new(), KeypadState::new())
+    }
+
+    fn strict_debug_state() -> State {
+        debug_state_with(State::new_strict)
+    }
+
+    #[test]
+    fn strict_state_test() {
+        // 0123 is a machine code call, then V0 = 7, a dump of V0..VF at 0xFFA and a return with an empty stack
+        let program = [0x01, 0x23, 0x60, 0x07, 0xAF, 0xFA, 0xFF, 0x55, 0x00, 0xEE];
+
+        let mut strict = strict_debug_state();
+        strict.initialize(&program, &DEFAULT_FONT);
+        assert_eq!(strict.execute(), Err(ExecutionError::InvalidOpcode(0x0123)));
+        assert_eq!(strict.pc, 0x200);
+        strict.pc = 0x202;
+        strict.execute().unwrap();
+        strict.execute().unwrap();
+        assert_eq!(strict.execute(), Err(ExecutionError::MemoryOutOfBounds(0x1000)));
+        assert_eq!(strict.memory()[0xFFA], 0);
+        strict.pc = 0x208;
+        assert_eq!(strict.execute(), Err(ExecutionError::StackUnderflow));
+
+        let mut permissive = debug_state();
+        permissive.initialize(&program, &DEFAULT_FONT);
+        for _ in 0..5 {
+            permissive.execute().unwrap();
+        }
+        assert_eq!(permissive.gp_registers[0], 7);
+        // the dump wrapped around to the start of memory
+        assert_eq!(permissive.memory()[0xFFA], 7);
+        assert_eq!(permissive.memory()[0x000], 0);
+        // the return was skipped
+        assert_eq!(permissive.pc, 0x20A);
+    }
+
+    #[test]
+    fn strict_stack_test() {
+        let mut state = strict_debug_state();
+        // calls itself forever
+        state.initialize(&[0x22, 0x00], &DEFAULT_FONT);
+        for _ in 0..STACK_SIZE {
+            state.execute().unwrap();
+        }
+        assert_eq!(state.execute(), Err(ExecutionError::StackOverflow));
+
+        state.initialize(&[0x00, 0xEE], &DEFAULT_FONT);
+        state.stack.clear();
+        assert_eq!(state.execute(), Err(ExecutionError::StackUnderflow));
+    }
+
+    #[test]
+    fn stack_policy_test() {
+        let mut state = debug_state();
+        state.initialize(&[0x00, 0xEE], &DEFAULT_FONT);
+        state.set_stack_policy(StackPolicy::Error);
+        assert_eq!(state.execute(), Err(ExecutionError::StackUnderflow));
+        assert_eq!(state.pc, 0x200);
+
+        state.set_stack_policy(StackPolicy::Saturate);
+        assert_eq!(state.execute(), Ok(StepOutcome::Executed));
+        assert_eq!(state.pc, 0x202);
+
+        // a full stack keeps its return addresses, the call still jumps
+        let mut state = strict_debug_state();
+        state.set_stack_policy(StackPolicy::Saturate);
+        state.initialize(&[0x22, 0x00], &DEFAULT_FONT);
+        for _ in 0..=STACK_SIZE {
+            state.execute().unwrap();
+        }
+        assert_eq!((state.stack_depth(), state.pc), (STACK_SIZE, 0x200));
+    }
+
+    #[test]
+    #[should_panic(expected = "return with empty stack")]
+    fn stack_policy_panic_test() {
+        let mut state = debug_state();
+        state.set_stack_policy(StackPolicy::Panic);
+        state.initialize(&[0x00, 0xEE], &DEFAULT_FONT);
+        let _ = state.execute();
+    }
+
+    #[test]
+    fn sound_hook_test() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let hook_events = events.clone();
+
+        let mut state = debug_state();
+        state.set_sound_hook(Box::new(move |on| hook_events.lock().unwrap().push(on)));
+        // V0 = 2, ST = V0
+        state.initialize(&[0x60, 0x02, 0xF0, 0x18], &DEFAULT_FONT);
+        state.execute().unwrap();
+        state.execute().unwrap();
+        assert_eq!(*events.lock().unwrap(), [true]);
+
+        state.tick_timers();
+        assert_eq!(*events.lock().unwrap(), [true]);
+        state.tick_timers();
+        assert_eq!(*events.lock().unwrap(), [true, false]);
+        // already stopped, no further edge
+        state.tick_timers();
+        assert_eq!(*events.lock().unwrap(), [true, false]);
+    }
+
+    #[test]
+    fn dump_registers_test() {
+        let mut state = debug_state();
+        // V0 = 0x12, VA = 0xFF, I = 0x300
+        state.initialize(&[0x60, 0x12, 0x6A, 0xFF, 0xA3, 0x00], &DEFAULT_FONT);
+        for _ in 0..3 {
+            state.execute().unwrap();
+        }
+        let dump = state.dump_registers();
+        assert!(dump.contains("V0=12"));
+        assert!(dump.contains("VA=ff"));
+        assert!(dump.contains("PC=0x206"));
+        assert!(dump.contains("I=0x300"));
+        assert!(dump.contains("SP=0"));
+        assert_eq!(dump.lines().count(), 6);
+    }
+
+    #[test]
+    fn sprite_start_test() {
+        let mut buffer = DisplayBuffer::new();
+        buffer.modify(&[0x80], 1, 68, 0);
+        assert!(buffer.get_pixel(4, 0));
+
+        let mut buffer = DisplayBuffer::new();
+        buffer.set_sprite_start(SpriteStart::Clip);
+        buffer.modify(&[0x80], 1, 68, 0);
+        assert!(buffer.display.iter().all(|p| !p));
+        // inside the display the start position is used as is
+        buffer.modify(&[0x80], 1, 4, 0);
+        assert!(buffer.get_pixel(4, 0));
+    }
+
+    #[test]
+    fn run_debug_test() {
+        let mut state = debug_state();
+        // V3 += 1, jump back
+        state.initialize(&[0x73, 0x01, 0x12, 0x00], &DEFAULT_FONT);
+        let mut seen = Vec::new();
+        let run = state
+            .run_debug(100, |s, instruction| {
+                seen.push(*instruction);
+                if s.gp_registers[3] == 5 {
+                    DebugAction::Abort
+                } else {
+                    DebugAction::Continue
+                }
+            })
+            .unwrap();
+        assert_eq!(run, DebugRun { steps: 9, stop: DebugStop::Aborted, stack_depth: 0 });
+        assert_eq!(state.gp_registers[3], 5);
+        assert_eq!(state.pc, 0x202);
+        assert_eq!(seen[0], Instruction::AddConst { x: 3, nn: 1 });
+        assert_eq!(seen[9], Instruction::Jump { nnn: 0x200 });
+
+        let run = state.run_debug(3, |_, _| DebugAction::Continue).unwrap();
+        assert_eq!(run, DebugRun { steps: 3, stop: DebugStop::BudgetExhausted, stack_depth: 0 });
+        assert_eq!(state.gp_registers[3], 6);
+
+        let run = state.run_debug(3, |_, _| DebugAction::Pause).unwrap();
+        assert_eq!(run, DebugRun { steps: 0, stop: DebugStop::Paused, stack_depth: 0 });
+    }
+
+    #[test]
+    fn stack_depth_test() {
+        let mut state = debug_state();
+        // call 0x204, which spins without returning
+        state.initialize(&[0x22, 0x04, 0x00, 0x00, 0x12, 0x04], &DEFAULT_FONT);
+        assert_eq!(state.stack_depth(), 0);
+        let run = state.run_debug(10, |_, _| DebugAction::Continue).unwrap();
+        assert_eq!(run.stop, DebugStop::BudgetExhausted);
+        assert_eq!(run.stack_depth, 1);
+        assert_eq!(state.stack_depth(), 1);
+    }
+
+    #[test]
+    fn set_resolution_test() {
+        let mut buffer = DisplayBuffer::new();
+        buffer.set_sprite_start(SpriteStart::Clip);
+        buffer.set_resolution(128, 64);
+        assert_eq!((buffer.width(), buffer.height()), (128, 64));
+        assert_eq!(buffer.display.len(), 128 * 64);
+
+        buffer.modify(&[0xFF], 1, 120, 60);
+        assert!(buffer.get_pixel(120, 60));
+        assert!(buffer.get_pixel(127, 60));
+
+        buffer.set_resolution(64, 32);
+        assert_eq!((buffer.width(), buffer.height()), (64, 32));
+        assert!(buffer.display.iter().all(|p| !p));
+        buffer.modify(&[0xFF], 1, 120, 60);
+        assert!(buffer.display.iter().all(|p| !p));
+    }
+
+    #[test]
+    fn cycle_cost_test() {
+        let draw = Instruction::decode(0xD125);
+        let mov = Instruction::decode(0x8120);
+        assert!(draw.cycle_cost() > mov.cycle_cost());
+
+        let mut state = debug_state();
+        state.initialize(&[0x81, 0x20, 0xD1, 0x25], &DEFAULT_FONT);
+        state.execute().unwrap();
+        state.execute().unwrap();
+        assert_eq!(state.machine_cycles(), (draw.cycle_cost() + mov.cycle_cost()) as u64);
+    }
+
+    #[test]
+    fn find_pattern_test() {
+        let mut state = debug_state();
+        state.initialize(&[0x12, 0x00, 0x3C, 0x42, 0x81, 0x00, 0x3C, 0x42, 0x81], &DEFAULT_FONT);
+        assert_eq!(state.find_pattern(&[0x3C, 0x42, 0x81]), [0x202, 0x206]);
+        // the '0' glyph of the font
+        assert_eq!(state.find_pattern(&DEFAULT_FONT[0..5]), [FONT_START]);
+        assert!(state.find_pattern(&[]).is_empty());
+    }
 
-        if nibbles[0] == 0 {
-            if nibbles[1] == 0 && nibbles[2] == 0xE && nibbles[3] == 0 {
-                return Instruction::Cls;
-            } else if nibbles[1] == 0 && nibbles[2] == 0xE && nibbles[3] == 0xE {
-                return Instruction::Rts;
-            } else {
-                return Instruction::Invalid;
+    #[test]
+    fn from_buffers_test() {
+        let (mut state, peripherals) = buffer_state();
+        // draw the '0' glyph at (0, 0), then V0 = 3 and set the sound timer to V0
+        state.initialize(&[0xA0, 0x50, 0xD0, 0x05, 0x60, 0x03, 0xF0, 0x18], &DEFAULT_FONT);
+        for _ in 0..4 {
+            state.execute().unwrap();
+        }
+        let display = peripherals.display.lock().unwrap();
+        assert!(display.get_pixel(0, 0));
+        assert!(display.get_pixel(3, 4));
+        assert!(!display.get_pixel(1, 1));
+        assert!(peripherals.sound_timer.lock().unwrap().is_beeping());
+
+        peripherals.keypad.lock().unwrap().press(0xA);
+        assert_eq!(peripherals.keypad.lock().unwrap().get_pressed_key(), Some(0xA));
+    }
+
+    #[test]
+    fn execute_opcode_test() {
+        let mut state = debug_state();
+        state.initialize(&[0x12, 0x00], &DEFAULT_FONT);
+        state.execute_opcode(0x6005).unwrap();
+        assert_eq!(state.gp_registers[0], 5);
+        assert_eq!(state.pc, 0x200);
+        assert_eq!(state.memory()[0x200..0x202], [0x12, 0x00]);
+
+        // skips are relative to the current pc
+        state.execute_opcode(0x3005).unwrap();
+        assert_eq!(state.pc, 0x202);
+        state.execute_opcode(0x1300).unwrap();
+        assert_eq!(state.pc, 0x300);
+        state.execute_opcode(0x2400).unwrap();
+        assert_eq!((state.pc, state.stack.as_slice()), (0x400, [0x300].as_slice()));
+    }
+
+    #[test]
+    fn pressed_key_mask_test() {
+        let mut state = State::new(
+            Arc::new(Mutex::new(DisplayBuffer::new())),
+            Arc::new(Mutex::new(DebugTimer { value: 0 })),
+            Arc::new(Mutex::new(DebugBeeper { value: 0 })),
+            Arc::new(Mutex::new(DebugKeypad {
+                currently_pressed: Some(0x1A),
+            })),
+        );
+        // V1 = key, V2 = 0x0A, skip if key V2 is pressed
+        state.initialize(&[0xF1, 0x0A, 0x62, 0x0A, 0xE2, 0x9E], &DEFAULT_FONT);
+        for _ in 0..3 {
+            state.execute().unwrap();
+        }
+        assert_eq!(state.gp_registers[1], 0x0A);
+        assert_eq!(state.pc, 0x208);
+    }
+
+    #[test]
+    fn vf_watch_test() {
+        let writes = Arc::new(Mutex::new(Vec::new()));
+        let watch_writes = writes.clone();
+
+        let mut state = debug_state();
+        state.set_vf_watch(Box::new(move |value, mnemonic| watch_writes.lock().unwrap().push((value, mnemonic))));
+        // V0 = 0xFF, V1 = 0x01, V0 += V1, V2 = 3
+        state.initialize(&[0x60, 0xFF, 0x61, 0x01, 0x80, 0x14, 0x62, 0x03], &DEFAULT_FONT);
+        for _ in 0..4 {
+            state.execute().unwrap();
+        }
+        assert_eq!(*writes.lock().unwrap(), [(1, "ADD")]);
+
+        // VF as destination register
+        state.execute_opcode(0x6F07).unwrap();
+        assert_eq!(writes.lock().unwrap()[1], (7, "LD"));
+    }
+
+    #[test]
+    fn dimensions_test() {
+        let buffer = DisplayBuffer::new();
+        assert_eq!(buffer.dimensions(), (64, 32));
+
+        let (state, peripherals) = buffer_state();
+        assert_eq!(state.display_dimensions(), (64, 32));
+        peripherals.display.lock().unwrap().set_resolution(128, 64);
+        assert_eq!(state.display_dimensions(), (128, 64));
+    }
+
+    #[test]
+    fn run_until_test() {
+        let mut state = debug_state();
+        // V0 += 1, jump back
+        state.initialize(&[0x70, 0x01, 0x12, 0x00], &DEFAULT_FONT);
+        assert!(state.run_until(100, |s| s.gp_registers[0] == 5).unwrap());
+        assert_eq!(state.gp_registers[0], 5);
+        assert_eq!(state.pc, 0x202);
+
+        assert!(!state.run_until(10, |s| s.gp_registers[0] == 0xFF).unwrap());
+        assert_eq!(state.gp_registers[0], 10);
+    }
+
+    #[test]
+    fn add_i_wrap_test() {
+        let mut state = debug_state();
+        state.gp_registers[0] = 1;
+        state.index_reg = 0x0FFF;
+        state.execute_opcode(0xF01E).unwrap();
+        assert_eq!(state.index_reg, 0x0000);
+
+        let mut state = debug_state();
+        state.set_memory_size(MemorySize::XoChip);
+        assert_eq!(state.memory().len(), 0x10000);
+        state.gp_registers[0] = 1;
+        state.index_reg = 0x0FFF;
+        state.execute_opcode(0xF01E).unwrap();
+        assert_eq!(state.index_reg, 0x1000);
+        state.index_reg = 0xFFFF;
+        state.execute_opcode(0xF01E).unwrap();
+        assert_eq!(state.index_reg, 0x0000);
+    }
+
+    #[test]
+    fn packed_bytes_test() {
+        let mut buffer = DisplayBuffer::new();
+        buffer.modify(&DEFAULT_FONT[0..5], 5, 3, 7);
+        buffer.modify(&[0xA5, 0xFF], 2, 60, 30);
+        let original = buffer.display.clone();
+
+        let packed = buffer.as_packed_bytes();
+        assert_eq!(packed.len(), 256);
+        buffer.clear();
+        assert_ne!(buffer.display, original);
+        buffer.load_packed_bytes(&packed).unwrap();
+        assert_eq!(buffer.display, original);
+
+        assert_eq!(
+            buffer.load_packed_bytes(&packed[1..]),
+            Err(BufferSizeError { expected: 256, actual: 255 })
+        );
+        assert_eq!(buffer.display, original);
+    }
+
+    #[test]
+    fn uninitialized_read_test() {
+        let reads = Arc::new(Mutex::new(Vec::new()));
+        let hook_reads = reads.clone();
+
+        let mut state = debug_state();
+        state.set_uninitialized_read_hook(Box::new(move |register, address| hook_reads.lock().unwrap().push((register, address))));
+        state.set_quirks(Quirks { shift_uses_vy: true, ..Quirks::default() });
+        // V0 = 1, V1 = V0, V2 = V5, V5 = 2, V3 = V5, V7 = V6 >> 1
+        state.initialize(&[0x60, 0x01, 0x81, 0x00, 0x82, 0x50, 0x65, 0x02, 0x83, 0x50, 0x87, 0x66], &DEFAULT_FONT);
+        for _ in 0..6 {
+            state.execute().unwrap();
+        }
+        // the shift reads V6, not V7
+        assert_eq!(*reads.lock().unwrap(), [(5, 0x204), (6, 0x20A)]);
+    }
+
+    #[test]
+    fn opcode_log_test() {
+        let mut state = debug_state();
+        // V0 = 2, call 0x206, jump 0x200, V0 += 1, return
+        state.initialize(&[0x60, 0x02, 0x22, 0x06, 0x12, 0x00, 0x70, 0x01, 0x00, 0xEE], &DEFAULT_FONT);
+        state.execute().unwrap();
+        assert!(state.opcode_log().is_empty());
+
+        state.set_opcode_logging(true);
+        for _ in 0..4 {
+            state.execute().unwrap();
+        }
+        assert_eq!(state.opcode_log(), [(0x202, 0x2206), (0x206, 0x7001), (0x208, 0x00EE), (0x204, 0x1200)]);
+
+        assert_eq!(state.drain_opcode_log().len(), 4);
+        state.execute().unwrap();
+        assert_eq!(state.opcode_log(), [(0x200, 0x6002)]);
+    }
+
+    #[test]
+    fn preview_sprite_test() {
+        let mut state = debug_state();
+        state.initialize(&[], &DEFAULT_FONT);
+        let o = [true, true, true, true, false, false, false, false];
+        let l = [true, false, false, true, false, false, false, false];
+        assert_eq!(state.preview_sprite(FONT_START, 5), [o, l, l, l, o]);
+        assert_eq!(state.preview_sprite(0xFFE, 5).len(), 2);
+        assert!(state.preview_sprite(0x2000, 5).is_empty());
+    }
+
+    #[test]
+    fn shift_quirk_test() {
+        let mut state = debug_state();
+        state.gp_registers[1] = 0b0000_0110;
+        state.gp_registers[2] = 0b1000_0001;
+        state.execute_opcode(0x8126).unwrap();
+        assert_eq!((state.gp_registers[1], state.gp_registers[0xF]), (0b0000_0011, 0));
+        state.gp_registers[1] = 0b0000_0110;
+        state.execute_opcode(0x812E).unwrap();
+        assert_eq!((state.gp_registers[1], state.gp_registers[0xF]), (0b0000_1100, 0));
+
+        let mut state = debug_state();
+        state.set_quirks(Quirks {
+            shift_uses_vy: true,
+            ..Quirks::default()
+        });
+        state.gp_registers[1] = 0b0000_0110;
+        state.gp_registers[2] = 0b1000_0001;
+        state.execute_opcode(0x8126).unwrap();
+        assert_eq!((state.gp_registers[1], state.gp_registers[0xF]), (0b0100_0000, 1));
+        state.execute_opcode(0x812E).unwrap();
+        assert_eq!((state.gp_registers[1], state.gp_registers[0xF]), (0b0000_0010, 1));
+        assert_eq!(state.gp_registers[2], 0b1000_0001);
+    }
+
+    #[test]
+    fn memory_test() {
+        let mut state = debug_state();
+        let program = [0x60, 0x05, 0x12, 0x02];
+        state.initialize(&program, &DEFAULT_FONT);
+        assert_eq!(state.memory().len(), MEM_SIZE);
+        assert_eq!(state.memory()[PROGRAM_START..PROGRAM_START + 4], program);
+        state.set_memory_size(MemorySize::XoChip);
+        assert_eq!(state.memory().len(), 0x10000);
+    }
+
+    #[test]
+    fn headless_test() {
+        let mut state = State::headless();
+        // V0 = 0xF0, V1 = 0x20, V0 += V1, V2 = V1, V2 -= V0
+        state.initialize(&[0x60, 0xF0, 0x61, 0x20, 0x80, 0x14, 0x82, 0x10, 0x82, 0x05, 0xD0, 0x15], &DEFAULT_FONT);
+        state.run_until(5, |_| false).unwrap();
+        assert_eq!(state.gp_registers[0], 0x10);
+        assert_eq!(state.gp_registers[2], 0x10);
+        assert_eq!(state.gp_registers[0xF], 1);
+        // the null display never reports a collision
+        state.execute().unwrap();
+        assert_eq!(state.gp_registers[0xF], 0);
+    }
+
+    #[test]
+    fn combine_nibbles_test() {
+        assert_eq!(Instruction::combine_nibbles(&[0xA, 0xB, 0xC, 0xD]), 0xABCD);
+        let nibbles = Instruction::code_to_nibble_array(0xABCD);
+        assert_eq!(Instruction::combine_nibbles(&nibbles), 0xABCD);
+        assert_eq!(Instruction::combine_nibbles(&nibbles[1..]), 0x0BCD);
+        assert_eq!(Instruction::combine_nibbles(&nibbles[2..]), 0x00CD);
+        assert_eq!(Instruction::combine_nibbles(&[]), 0);
+    }
+
+    #[test]
+    fn execute_burst_test() {
+        // V0 += 3, V1 ^= V0, I += V1, jump back
+        let program = [0x70, 0x03, 0x81, 0x03, 0xF1, 0x1E, 0x12, 0x00];
+        let mut single = debug_state();
+        let mut burst = debug_state();
+        single.initialize(&program, &DEFAULT_FONT);
+        burst.initialize(&program, &DEFAULT_FONT);
+        for _ in 0..50 {
+            single.execute().unwrap();
+        }
+        assert_eq!(burst.execute_burst(50), Ok(50));
+        assert!(burst.cpu_state_eq(&single));
+
+        // a jump to itself and waiting for a key do not end the burst early
+        for program in [[0x60, 0x01, 0x12, 0x02], [0x60, 0x01, 0xF0, 0x0A]] {
+            let mut single = debug_state();
+            let mut burst = debug_state();
+            for state in [&mut single, &mut burst] {
+                state.set_max_wait_spins(Some(3));
+                state.initialize(&program, &DEFAULT_FONT);
             }
+            let mut outcome = Ok(StepOutcome::Executed);
+            for _ in 0..10 {
+                outcome = single.execute();
+            }
+            assert_eq!(burst.execute_burst(10), Ok(10));
+            assert!(burst.cpu_state_eq(&single));
+            assert_eq!(burst.machine_cycles(), single.machine_cycles());
+            assert_eq!(burst.pc, 0x202);
+            // the next instruction sees the same spin count
+            assert_eq!(burst.execute(), single.execute());
+            assert_eq!(outcome == Ok(StepOutcome::StalledOnKey), program[2] == 0xF0);
         }
 
-        if nibbles[0] == 1 {
-            return Instruction::Jump {
-                nnn: Instruction::combine_nibbles(&nibbles[1..]),
-            };
+        // V0 = 1, V1 = 2, return without a call
+        let mut burst = strict_debug_state();
+        burst.initialize(&[0x60, 0x01, 0x61, 0x02, 0x00, 0xEE], &DEFAULT_FONT);
+        assert_eq!(burst.execute_burst(10), Err((2, ExecutionError::StackUnderflow)));
+        assert_eq!(burst.gp_registers[1], 2);
+    }
+
+    #[test]
+    fn key_label_test() {
+        let labels = "0123456789ABCDEF";
+        for (key, label) in labels.chars().enumerate() {
+            assert_eq!(key_label(key as u8), Some(label));
+            assert_eq!(key_from_label(label), Some(key as u8));
         }
+        assert_eq!(key_from_label('c'), Some(0xC));
+        assert_eq!(key_label(0x10), None);
+        assert_eq!(key_label(0xFF), None);
+        assert_eq!(key_from_label('G'), None);
+    }
 
-        if nibbles[0] == 2 {
-            return Instruction::Call {
-                nnn: Instruction::combine_nibbles(&nibbles[1..]),
-            };
+    #[test]
+    fn set_display_test() {
+        let (mut state, peripherals) = buffer_state();
+        // I = '0' glyph, draw at (0, 0) twice
+        state.initialize(&[0xA0, 0x50, 0xD0, 0x05, 0xD0, 0x05], &DEFAULT_FONT);
+        state.execute().unwrap();
+        state.execute().unwrap();
+        assert!(peripherals.display.lock().unwrap().get_pixel(0, 0));
+
+        let mut preserved = DisplayBuffer::new();
+        preserved.modify(&[0x80], 1, 10, 10);
+        let second = Arc::new(Mutex::new(preserved));
+        state.set_display(second.clone(), false);
+        state.execute().unwrap();
+        // the draw went to the new display, the old one is untouched
+        assert!(second.lock().unwrap().get_pixel(0, 0));
+        assert!(second.lock().unwrap().get_pixel(10, 10));
+        assert!(peripherals.display.lock().unwrap().get_pixel(0, 0));
+
+        state.set_display(second.clone(), true);
+        assert!(!second.lock().unwrap().get_pixel(10, 10));
+    }
+
+    #[test]
+    fn call_stack_test() {
+        let mut state = debug_state();
+        // call 0x204, nop, call 0x208, nop, spin
+        state.initialize(&[0x22, 0x04, 0x00, 0x00, 0x22, 0x08, 0x00, 0x00, 0x12, 0x08], &DEFAULT_FONT);
+        state.execute().unwrap();
+        state.execute().unwrap();
+        assert_eq!(state.call_stack(), [0x202, 0x206]);
+        assert_eq!(state.stack_depth(), 2);
+    }
+
+    #[test]
+    fn reinitialize_test() {
+        let (mut state, peripherals) = buffer_state();
+        // V3 = 7, I = '0' glyph, draw, call 0x300
+        state.initialize(&[0x63, 0x07, 0xA0, 0x50, 0xD0, 0x05, 0x23, 0x00], &DEFAULT_FONT);
+        for _ in 0..4 {
+            state.execute().unwrap();
         }
+        assert!(peripherals.display.lock().unwrap().get_pixel(0, 0));
+        peripherals.delay_timer.lock().unwrap().set(9);
+        peripherals.sound_timer.lock().unwrap().start(9);
+        state.wait_spins = 2;
+        state.latched_key = Some(3);
+        state.frame_collision = true;
 
-        if nibbles[0] == 3 {
-            return Instruction::SkipEqConst {
-                x: nibbles[1] as u8,
-                nn: Instruction::combine_nibbles(&nibbles[2..]) as u8,
-            };
+        state.initialize(&[0x12, 0x00], &DEFAULT_FONT);
+        assert!(peripherals.display.lock().unwrap().display.iter().all(|p| !p));
+        assert_eq!(state.gp_registers, [0; 16]);
+        assert_eq!((state.pc, state.index_reg, state.stack_depth()), (PROGRAM_START, 0, 0));
+        assert_eq!(peripherals.delay_timer.lock().unwrap().get(), 0);
+        assert!(!state.should_beep());
+        assert_eq!((state.wait_spins, state.latched_key, state.frame_collision()), (0, None, false));
+        // no leftovers of the longer previous program
+        assert_eq!(state.memory()[0x202..0x208], [0; 6]);
+
+        state.set_clear_display_on_init(false);
+        state.initialize(&[0xA0, 0x50, 0xD0, 0x05], &DEFAULT_FONT);
+        state.execute().unwrap();
+        state.execute().unwrap();
+        state.initialize(&[0x12, 0x00], &DEFAULT_FONT);
+        assert!(peripherals.display.lock().unwrap().get_pixel(0, 0));
+    }
+
+    #[test]
+    fn font_digit_at_index_test() {
+        let mut state = debug_state();
+        state.initialize(&[0x60, 0x0C, 0xF0, 0x29], &DEFAULT_FONT);
+        assert_eq!(state.font_digit_at_index(), None);
+        state.execute().unwrap();
+        state.execute().unwrap();
+        assert_eq!(state.font_digit_at_index(), Some(0xC));
+
+        state.index_reg += 1;
+        assert_eq!(state.font_digit_at_index(), None);
+        state.index_reg = (FONT_START + 16 * FONT_CHARACTER_BYTES) as u16;
+        assert_eq!(state.font_digit_at_index(), None);
+    }
+
+    #[test]
+    fn zero_size_display_test() {
+        let mut state = State::new(
+            Arc::new(Mutex::new(DebugDisplay {
+                ret: true,
+                width: 0,
+                height: 0,
+            })),
+            Arc::new(Mutex::new(DebugTimer { value: 0 })),
+            Arc::new(Mutex::new(DebugBeeper { value: 0 })),
+            Arc::new(Mutex::new(DebugKeypad { currently_pressed: None })),
+        );
+        state.initialize(&[0xA0, 0x50, 0xD0, 0x05], &DEFAULT_FONT);
+        state.execute().unwrap();
+        state.execute().unwrap();
+        assert_eq!(state.gp_registers[0xF], 0);
+
+        let mut buffer = DisplayBuffer::new();
+        buffer.set_resolution(0, 0);
+        assert!(!buffer.modify(&DEFAULT_FONT[0..5], 5, 3, 3));
+    }
+
+    #[test]
+    fn decode_detailed_test() {
+        assert_eq!(Instruction::decode_detailed(0x5AB0), Ok(Instruction::SkipEq { x: 0xA, y: 0xB }));
+        assert_eq!(Instruction::decode_detailed(0x5AB1), Err(DecodeError::BadLowNibble { family: 0x5 }));
+        assert_eq!(Instruction::decode_detailed(0x812F), Err(DecodeError::BadLowNibble { family: 0x8 }));
+        assert_eq!(Instruction::decode_detailed(0x0123), Err(DecodeError::MachineCodeCall { nnn: 0x123 }));
+        assert_eq!(Instruction::decode_detailed(0xE1FF), Err(DecodeError::ReservedPattern { family: 0xE }));
+        assert_eq!(Instruction::decode_detailed(0xF000), Err(DecodeError::ReservedPattern { family: 0xF }));
+    }
+
+    #[test]
+    fn trace_matches_test() {
+        let mut state = debug_state();
+        // V0 = 1, skip if V0 == 1, jump 0x200, call 0x20A, spin, return
+        state.initialize(&[0x60, 0x01, 0x30, 0x01, 0x12, 0x00, 0x22, 0x0A, 0x12, 0x08, 0x00, 0xEE], &DEFAULT_FONT);
+        assert_trace_matches(
+            &mut state,
+            &[(0x200, 0x6001), (0x202, 0x3001), (0x206, 0x220A), (0x20A, 0x00EE), (0x208, 0x1208), (0x208, 0x1208)],
+            6,
+        );
+    }
+
+    #[test]
+    fn scroll_test() {
+        let mut buffer = DisplayBuffer::new();
+        buffer.modify(&[0x80], 1, 8, 4);
+        buffer.scroll_down(1);
+        assert!(buffer.get_pixel(8, 5));
+        buffer.scroll_up(2);
+        assert!(buffer.get_pixel(8, 3));
+        buffer.scroll_right();
+        assert!(buffer.get_pixel(12, 3));
+        buffer.scroll_left();
+        assert!(buffer.get_pixel(8, 3));
+        assert_eq!(buffer.display.iter().filter(|p| **p).count(), 1);
+
+        // SUPER-CHIP scrolls by half a pixel in lo-res
+        let mut buffer = DisplayBuffer::new();
+        buffer.set_scroll_units(ScrollUnits::SuperChip);
+        buffer.modify(&[0x80], 1, 8, 4);
+        buffer.scroll_down(1);
+        assert!(buffer.get_pixel(8, 4));
+        buffer.scroll_down(2);
+        assert!(buffer.get_pixel(8, 5));
+        buffer.scroll_right();
+        assert!(buffer.get_pixel(10, 5));
+
+        // in hi-res both count whole pixels
+        buffer.set_resolution(128, 64);
+        buffer.modify(&[0x80], 1, 8, 4);
+        buffer.scroll_down(1);
+        assert!(buffer.get_pixel(8, 5));
+
+        // content scrolled out is lost
+        buffer.scroll_down(64);
+        assert!(buffer.display.iter().all(|p| !p));
+    }
+
+    #[test]
+    fn frame_collision_test() {
+        let (mut state, _peripherals) = buffer_state();
+        // I = '0' glyph, draw at (0, 0) twice, then at (V1, 0) with V1 = 20
+        state.initialize(&[0xA0, 0x50, 0xD0, 0x05, 0xD0, 0x05, 0x61, 0x14, 0xD1, 0x05], &DEFAULT_FONT);
+        state.run_until(3, |_| false).unwrap();
+        assert!(state.frame_collision());
+        state.run_until(2, |_| false).unwrap();
+        assert_eq!(state.gp_registers[0xF], 0);
+        assert!(state.frame_collision());
+
+        state.clear_frame_collision();
+        assert!(!state.frame_collision());
+    }
+
+    #[test]
+    fn add_i_overflow_quirk_test() {
+        for (memory_size, overflow_quirk, expected_i, expected_vf) in [
+            (MemorySize::Classic, false, 0x0000, 5),
+            (MemorySize::Classic, true, 0x0000, 1),
+            (MemorySize::XoChip, false, 0x1000, 5),
+            (MemorySize::XoChip, true, 0x1000, 0),
+        ] {
+            let mut state = debug_state();
+            state.set_memory_size(memory_size);
+            state.set_quirks(Quirks {
+                add_i_sets_overflow: overflow_quirk,
+                ..Quirks::default()
+            });
+            state.index_reg = 0x0FFF;
+            state.gp_registers[0] = 0x01;
+            state.gp_registers[0xF] = 5;
+            state.execute_opcode(0xF01E).unwrap();
+            assert_eq!((state.index_reg, state.gp_registers[0xF]), (expected_i, expected_vf));
         }
+    }
 
-        if nibbles[0] == 4 {
-            return Instruction::SkipNeqConst {
-                x: nibbles[1] as u8,
-                nn: Instruction::combine_nibbles(&nibbles[2..]) as u8,
-            };
+    #[test]
+    fn bitmap_test() {
+        // first frame of a ROM drawing '0' at (0, 0) and '1' at (8, 0), the first 5 rows of the 64x32 reference
+        const REFERENCE_ROWS: [u8; 40] = [
+            0xF0, 0x20, 0, 0, 0, 0, 0, 0,
+            0x90, 0x60, 0, 0, 0, 0, 0, 0,
+            0x90, 0x20, 0, 0, 0, 0, 0, 0,
+            0x90, 0x20, 0, 0, 0, 0, 0, 0,
+            0xF0, 0x70, 0, 0, 0, 0, 0, 0,
+        ];
+        let mut reference = vec![0; 256];
+        reference[..40].copy_from_slice(&REFERENCE_ROWS);
+
+        let (mut state, peripherals) = buffer_state();
+        state.initialize(&[0xA0, 0x50, 0xD0, 0x05, 0x60, 0x08, 0xA0, 0x55, 0xD0, 0x15], &DEFAULT_FONT);
+        for _ in 0..5 {
+            state.execute().unwrap();
+        }
+        let bitmap = peripherals.display.lock().unwrap().to_bitmap();
+        assert_eq!(bitmap, reference);
+
+        let restored = DisplayBuffer::from_bitmap(64, 32, &bitmap).unwrap();
+        assert_eq!(restored.display, peripherals.display.lock().unwrap().display);
+
+        // rows are padded to whole bytes
+        let mut buffer = DisplayBuffer::from_bitmap(12, 2, &[0x80, 0x10, 0x00, 0x30]).unwrap();
+        assert!(buffer.get_pixel(0, 0) && buffer.get_pixel(11, 0) && buffer.get_pixel(10, 1) && buffer.get_pixel(11, 1));
+        assert_eq!(buffer.display.iter().filter(|on| **on).count(), 4);
+        assert_eq!(buffer.to_bitmap(), vec![0x80, 0x10, 0x00, 0x30]);
+        buffer.clear();
+        assert_eq!(buffer.to_bitmap(), vec![0; 4]);
+
+        assert_eq!(
+            DisplayBuffer::from_bitmap(12, 2, &[0; 3]).err(),
+            Some(BufferSizeError { expected: 4, actual: 3 })
+        );
+    }
+
+    #[test]
+    fn strict_bcd_reserved_memory_test() {
+        let mut state = strict_debug_state();
+        // V0 = 123, I = 0x60 (inside the font), BCD
+        state.initialize(&[0x60, 0x7B, 0xA0, 0x60, 0xF0, 0x33], &DEFAULT_FONT);
+        state.execute().unwrap();
+        state.execute().unwrap();
+        let font = state.memory()[0x60..0x63].to_vec();
+        assert_eq!(state.execute(), Err(ExecutionError::ReservedMemoryWrite(0x60)));
+        assert_eq!(state.pc, 0x204);
+        assert_eq!(&state.memory()[0x60..0x63], &font[..]);
+
+        // permissive states still write
+        let mut state = debug_state();
+        state.initialize(&[0x60, 0x7B, 0xA0, 0x60, 0xF0, 0x33], &DEFAULT_FONT);
+        for _ in 0..3 {
+            state.execute().unwrap();
+        }
+        assert_eq!(&state.memory()[0x60..0x63], &[1, 2, 3]);
+    }
+
+    #[test]
+    fn memory_backend_test() {
+        use std::cell::Cell;
+        use std::rc::Rc;
+
+        struct TracingMemory {
+            inner: LinearMemory,
+            reads: Rc<Cell<usize>>,
+        }
+
+        impl Memory for TracingMemory {
+            fn read(&self, addr: usize) -> u8 {
+                self.reads.set(self.reads.get() + 1);
+                self.inner.read(addr)
+            }
+
+            fn write(&mut self, addr: usize, value: u8) {
+                self.inner.write(addr, value);
+            }
+
+            fn size(&self) -> usize {
+                self.inner.size()
+            }
+
+            fn resize(&mut self, size: usize, fill: u8) {
+                self.inner.resize(size, fill);
+            }
+        }
+
+        let reads = Rc::new(Cell::new(0));
+        let mut state = debug_state();
+        state.set_memory(Box::new(TracingMemory {
+            inner: LinearMemory::default(),
+            reads: reads.clone(),
+        }));
+        state.initialize(&[0x12, 0x00, 0x01, 0x02, 0x03, 0x04], &DEFAULT_FONT);
+        assert_eq!(reads.get(), 0);
+
+        // I = 0x202, load V0..V3
+        state.index_reg = 0x202;
+        state.execute_opcode(0xF365).unwrap();
+        assert_eq!(reads.get(), 4);
+        assert_eq!(state.gp_registers[..4], [1, 2, 3, 4]);
+
+        // fetching reads the two opcode bytes, without a slice inspecting the memory reads every byte
+        state.execute().unwrap();
+        assert_eq!(reads.get(), 6);
+        assert_eq!(state.find_pattern(&[0x01, 0x02]), vec![0x202]);
+        assert_eq!(reads.get(), 6 + MEM_SIZE);
+
+        // resizing keeps the backend and its content
+        state.set_memory_size(MemorySize::XoChip);
+        assert_eq!(state.memory().len(), 0x10000);
+        assert_eq!(state.instruction_at(0x202), Instruction::decode(0x0102));
+        assert_eq!(reads.get(), 6 + MEM_SIZE + 0x10000 + 2);
+    }
+
+    #[test]
+    fn step_describe_test() {
+        let mut state = debug_state();
+        // V0 = 5, I = 0x300, jump to 0x200
+        state.initialize(&[0x60, 0x05, 0xA3, 0x00, 0x12, 0x00], &DEFAULT_FONT);
+        assert_eq!(state.step_describe(), Ok((0x200, 0x6005, Instruction::MovConst { x: 0, nn: 0x05 })));
+        assert_eq!(state.step_describe(), Ok((0x202, 0xA300, Instruction::MovI { nnn: 0x300 })));
+        assert_eq!(state.step_describe(), Ok((0x204, 0x1200, Instruction::Jump { nnn: 0x200 })));
+        assert_eq!(state.pc, 0x200);
+    }
+
+    #[test]
+    fn draw_height_test() {
+        // n = 15 at I = 0xFF5 only has 11 rows left in memory
+        let (mut state, peripherals) = buffer_state();
+        state.initialize(&[0xAF, 0xF5, 0xD0, 0x0F], &DEFAULT_FONT);
+        state.write_bytes(0xFF5, &[0xFF; 11]);
+        state.execute().unwrap();
+        state.execute().unwrap();
+        let display = peripherals.display.lock().unwrap();
+        assert_eq!(display.display.iter().filter(|on| **on).count(), 11 * 8);
+        assert!(display.get_pixel(7, 10) && !display.get_pixel(0, 11));
+        drop(display);
+
+        // DXY0 draws nothing in lo-res and 16x16 in hi-res
+        state.index_reg = 0x300;
+        state.write_bytes(0x300, &[0xFF; 32]);
+        peripherals.display.lock().unwrap().clear();
+        state.execute_opcode(0xD000).unwrap();
+        assert!(peripherals.display.lock().unwrap().display.iter().all(|on| !on));
+
+        state.set_hires();
+        state.execute_opcode(0xD000).unwrap();
+        let display = peripherals.display.lock().unwrap();
+        assert_eq!(display.display.iter().filter(|on| **on).count(), 16 * 16);
+        assert!(display.get_pixel(15, 15) && !display.get_pixel(16, 0) && !display.get_pixel(0, 16));
+    }
+
+    #[test]
+    fn halt_program_test() {
+        let mut state = debug_state();
+        state.initialize(&halt_program(), &DEFAULT_FONT);
+        assert!(state.is_halted());
+        state.execute().unwrap();
+        assert!(state.is_halted());
+        assert_eq!(state.pc, PROGRAM_START);
+
+        state.initialize(&[0x60, 0x01, 0x12, 0x02], &DEFAULT_FONT);
+        assert!(!state.is_halted());
+        state.execute().unwrap();
+        assert!(state.is_halted());
+    }
+
+    #[test]
+    fn index_register_test() {
+        let (mut state, peripherals) = buffer_state();
+        state.initialize(&[0xD0, 0x02], &DEFAULT_FONT);
+        state.set_index_register(0x300);
+        assert_eq!(state.index_register(), 0x300);
+        state.write_bytes(0x300, &[0b1000_0001, 0b0100_0010]);
+        state.execute().unwrap();
+        let display = peripherals.display.lock().unwrap();
+        assert!(display.get_pixel(0, 0) && display.get_pixel(7, 0) && display.get_pixel(1, 1) && display.get_pixel(6, 1));
+        assert_eq!(display.display.iter().filter(|on| **on).count(), 4);
+        drop(display);
+
+        state.set_index_register(0x1234);
+        assert_eq!(state.index_register(), 0x234);
+    }
+
+    #[test]
+    fn memory_fill_test() {
+        let mut state = debug_state().with_memory_fill(0xFF);
+        assert!(state.memory().iter().all(|byte| *byte == 0xFF));
+        state.initialize(&[0x12, 0x00], &DEFAULT_FONT);
+        assert_eq!(state.memory()[0x202], 0xFF);
+        assert_eq!(state.memory()[0x000], 0xFF);
+        assert_eq!(state.memory()[FONT_START], DEFAULT_FONT[0]);
+        assert_eq!(state.memory()[PROGRAM_START], 0x12);
+        state.set_memory_size(MemorySize::XoChip);
+        assert_eq!(state.memory()[0xFFFF], 0xFF);
+
+        // RegLoad from unwritten memory
+        state.set_index_register(0x300);
+        state.execute_opcode(0xF065).unwrap();
+        assert_eq!(state.gp_registers[0], 0xFF);
+    }
+
+    #[test]
+    fn operands_test() {
+        let operands = Instruction::Draw { x: 1, y: 2, n: 5 }.operands();
+        assert_eq!((operands.x, operands.y, operands.n), (Some(1), Some(2), Some(5)));
+        assert_eq!((operands.nn, operands.nnn), (None, None));
+
+        assert_eq!(
+            Instruction::decode(0xC3AB).operands(),
+            Operands { x: Some(3), nn: Some(0xAB), ..Operands::default() }
+        );
+        assert_eq!(Instruction::decode(0x2345).operands().nnn, Some(0x345));
+        assert_eq!(Instruction::Cls.operands(), Operands::default());
+    }
+
+    #[test]
+    fn run_for_test() {
+        use std::time::Duration;
+
+        let clock = Clock { cpu_hz: 700, timer_hz: 60 };
+        let mut state = debug_state();
+        state.initialize(&[0x70, 0x01, 0x12, 0x00], &DEFAULT_FONT);
+        state.delay_timer.lock().unwrap().set(100);
+        state.set_opcode_logging(true);
+        state.run_for(Duration::from_millis(100), &clock).unwrap();
+        assert_eq!(state.opcode_log().len(), 70);
+        assert_eq!(state.delay_timer.lock().unwrap().get(), 94);
+
+        // fractions carry over, 100 calls of 1ms run the same 70 instructions and 6 ticks
+        state.drain_opcode_log();
+        for _ in 0..100 {
+            state.run_for(Duration::from_millis(1), &clock).unwrap();
+        }
+        assert_eq!(state.opcode_log().len(), 70);
+        assert_eq!(state.delay_timer.lock().unwrap().get(), 88);
+    }
+
+    #[test]
+    fn effects_test() {
+        let quirks = Quirks::default();
+        let effects = Instruction::RegDump { x: 5 }.effects(&quirks);
+        assert_eq!(effects.registers_read, 0b11_1111);
+        assert_eq!(effects.registers_written, 0);
+        assert!(effects.writes_memory && !effects.reads_memory);
+        assert!(!effects.display && !effects.keypad && !effects.timers);
+
+        let effects = Instruction::Draw { x: 1, y: 2, n: 5 }.effects(&quirks);
+        assert_eq!((effects.registers_read, effects.registers_written), (0b110, 1 << 0xF));
+        assert!(effects.reads_memory && effects.display);
+        assert!(Instruction::WaitKey { x: 0 }.effects(&quirks).keypad);
+        assert!(Instruction::SetSoundTimer { x: 0 }.effects(&quirks).timers);
+
+        // a shift reads VY instead of VX with the quirk, VX and VF are written either way
+        let shift = Instruction::RightShift { x: 1, y: 2 };
+        let effects = shift.effects(&Quirks { shift_uses_vy: false, ..Quirks::default() });
+        assert_eq!((effects.registers_read, effects.registers_written), (1 << 1, 1 << 1 | 1 << 0xF));
+        let effects = shift.effects(&Quirks { shift_uses_vy: true, ..Quirks::default() });
+        assert_eq!((effects.registers_read, effects.registers_written), (1 << 2, 1 << 1 | 1 << 0xF));
+
+        let add_i = Instruction::AddI { x: 3 };
+        assert_eq!(add_i.effects(&Quirks { add_i_sets_overflow: true, ..Quirks::default() }).registers_written, 1 << 0xF);
+        assert_eq!(add_i.effects(&Quirks { add_i_sets_overflow: false, ..Quirks::default() }).registers_written, 0);
+    }
+
+    #[test]
+    fn font_address_test() {
+        let mut state = debug_state();
+        state.initialize_with_font_at(&[0x60, 0x0A, 0xF0, 0x29], &DEFAULT_FONT, 0x1A0);
+        assert_eq!(state.memory()[0x1A0..0x1A0 + 80], DEFAULT_FONT);
+        assert_eq!(state.memory()[FONT_START], 0);
+        state.execute().unwrap();
+        state.execute().unwrap();
+        assert_eq!(state.index_reg as usize, 0x1A0 + 0xA * FONT_CHARACTER_BYTES);
+        assert_eq!(state.font_digit_at_index(), Some(0xA));
+
+        // a font at the end of the program
+        let mut rom = vec![0xF0, 0x29];
+        rom.extend_from_slice(&DEFAULT_FONT);
+        state.initialize_with_font_at(&rom, &[], 0x202);
+        state.execute().unwrap();
+        assert_eq!(state.index_reg, 0x202);
+    }
+
+    #[test]
+    fn trace_hash_test() {
+        // VF = random, V0 += VF with carry into VF, loop
+        let rom = [0xCF, 0xFF, 0x80, 0xF4, 0x12, 0x00];
+        let mut state = debug_state().with_rng_seed(42);
+        state.initialize(&rom, &DEFAULT_FONT);
+        let hash = state.trace_hash(300);
+        assert_eq!(hash, 0xF06D_D43B_841D_17C9);
+
+        let mut again = debug_state().with_rng_seed(42);
+        again.initialize(&rom, &DEFAULT_FONT);
+        assert_eq!(again.trace_hash(300), hash);
+
+        let mut other_seed = debug_state().with_rng_seed(43);
+        other_seed.initialize(&rom, &DEFAULT_FONT);
+        assert_ne!(other_seed.trace_hash(300), hash);
+    }
+
+    #[test]
+    fn branch_targets_test() {
+        let jump = Instruction::Jump { nnn: 0x345 };
+        assert!(jump.is_branch());
+        assert_eq!(jump.branch_targets(0x200, 0), vec![0x345]);
+        assert_eq!(Instruction::Call { nnn: 0x400 }.branch_targets(0x200, 0), vec![0x400]);
+        assert_eq!(Instruction::JumpIndexed { nnn: 0x300 }.branch_targets(0x200, 0x10), vec![0x310]);
+
+        let skip = Instruction::SkipEqConst { x: 0, nn: 1 };
+        assert!(skip.is_branch());
+        assert_eq!(skip.branch_targets(0x210, 0), vec![0x212, 0x214]);
+
+        assert!(Instruction::Rts.is_branch());
+        assert!(Instruction::Rts.branch_targets(0x200, 0).is_empty());
+
+        let mov = Instruction::MovConst { x: 0, nn: 1 };
+        assert!(!mov.is_branch());
+        assert!(mov.branch_targets(0x200, 0).is_empty());
+    }
+
+    #[test]
+    fn needs_present_test() {
+        let mut state = debug_state();
+        state.initialize(&[0x00, 0xE0, 0x60, 0x01, 0xD0, 0x01], &DEFAULT_FONT);
+        state.present_done();
+        assert!(!state.needs_present());
+        state.execute().unwrap();
+        assert!(state.needs_present());
+        state.present_done();
+        assert!(!state.needs_present());
+
+        // only display instructions set it
+        state.execute().unwrap();
+        assert!(!state.needs_present());
+        state.execute().unwrap();
+        assert!(state.needs_present());
+    }
+
+    #[test]
+    fn lores_dxy0_quirk_test() {
+        for (quirk, expected_pixels) in [(false, 0), (true, 16 * 8)] {
+            let (mut state, peripherals) = buffer_state();
+            state.set_quirks(Quirks {
+                lores_dxy0_draws_16_rows: quirk,
+                ..Quirks::default()
+            });
+            state.initialize(&[0xA3, 0x00, 0xD0, 0x00], &DEFAULT_FONT);
+            state.write_bytes(0x300, &[0xFF; 32]);
+            state.execute().unwrap();
+            state.execute().unwrap();
+            let display = peripherals.display.lock().unwrap();
+            assert_eq!(display.display.iter().filter(|on| **on).count(), expected_pixels);
         }
+    }
+
+    #[test]
+    fn lores_reset_test() {
+        let (mut state, peripherals) = buffer_state();
+        state.set_clear_display_on_init(false);
+        state.set_hires();
+        assert!(state.is_hires());
+        assert_eq!(state.display_dimensions(), (128, 64));
+        peripherals.display.lock().unwrap().modify(&[0xFF], 1, 100, 50);
+
+        state.initialize(&halt_program(), &DEFAULT_FONT);
+        assert!(!state.is_hires());
+        assert_eq!(state.display_dimensions(), (64, 32));
+        assert!(peripherals.display.lock().unwrap().display.iter().all(|on| !on));
+    }
+
+    #[test]
+    fn would_collide_test() {
+        let (mut state, peripherals) = buffer_state();
+        state.initialize(&[0xA0, 0x50, 0xD0, 0x05, 0xD0, 0x05], &DEFAULT_FONT);
+        state.execute().unwrap();
+        assert!(!peripherals.display.lock().unwrap().would_collide(&DEFAULT_FONT[..5], 5, 0, 0));
+        state.execute().unwrap();
+        assert_eq!(state.gp_registers[0xF], 0);
+
+        let display = peripherals.display.lock().unwrap();
+        let before = display.display.clone();
+        assert!(display.would_collide(&DEFAULT_FONT[..5], 5, 0, 0));
+        assert!(!display.would_collide(&DEFAULT_FONT[..5], 5, 4, 0));
+        assert_eq!(display.display, before);
+        drop(display);
+        state.execute().unwrap();
+        assert_eq!(state.gp_registers[0xF], 1);
+    }
+
+    #[test]
+    fn enabled_instructions_test() {
+        let mut state = debug_state();
+        state.initialize(&[0x60, 0x05, 0xC0, 0xFF], &DEFAULT_FONT);
+        state.set_enabled_instructions(InstructionSet::ALL & !InstructionSet::RAND);
+        state.execute().unwrap();
+        assert_eq!(state.execute(), Err(ExecutionError::DisabledInstruction(0xC0FF)));
+        assert_eq!((state.pc, state.gp_registers[0]), (0x202, 5));
+
+        state.set_enabled_instructions(InstructionSet::CONST | InstructionSet::ALU);
+        assert_eq!(state.execute_opcode(0x00E0), Err(ExecutionError::DisabledInstruction(0x00E0)));
+        assert!(state.execute_opcode(0x8014).is_ok());
+        assert!(InstructionSet::default().contains(InstructionSet::RAND | InstructionSet::DISPLAY));
+    }
+
+    #[test]
+    fn disasm_window_test() {
+        let mut state = debug_state();
+        state.initialize(&[0x60, 0x05, 0xA3, 0x00, 0x12, 0x00], &DEFAULT_FONT);
+        let window = state.disasm_window(0x200, 1, 2);
+        assert_eq!(
+            window,
+            vec![
+                (0x1FE, Instruction::decode(0x0000)),
+                (0x200, Instruction::MovConst { x: 0, nn: 0x05 }),
+                (0x202, Instruction::MovI { nnn: 0x300 }),
+                (0x204, Instruction::Jump { nnn: 0x200 }),
+            ]
+        );
+        assert_eq!(state.disasm_window(0x203, 0, 0), vec![(0x202, Instruction::MovI { nnn: 0x300 })]);
+
+        // cut off at both ends of memory
+        let addresses: Vec<usize> = state.disasm_window(0xFFE, 2, 4).iter().map(|(addr, _)| *addr).collect();
+        assert_eq!(addresses, vec![0xFFA, 0xFFC, 0xFFE]);
+        assert_eq!(state.disasm_window(0x002, 4, 0).len(), 2);
+    }
 
-        if nibbles[0] == 5 {
-            if nibbles[3] != 0 {
-                return Instruction::Invalid;
-            }
+    #[test]
+    fn load_store_increment_test() {
+        for (increment, expected_i) in [
+            (IndexIncrement::None, 0x300),
+            (IndexIncrement::X, 0x303),
+            (IndexIncrement::XPlus1, 0x304),
+        ] {
+            let mut state = debug_state();
+            state.set_quirks(Quirks {
+                load_store_increment: increment,
+                ..Quirks::default()
+            });
+            state.gp_registers[..4].copy_from_slice(&[1, 2, 3, 4]);
+            state.set_index_register(0x300);
+            state.execute_opcode(0xF355).unwrap();
+            assert_eq!(state.memory()[0x300..0x304], [1, 2, 3, 4]);
+            assert_eq!(state.index_register(), expected_i);
 
-            return Instruction::SkipEq {
-                x: nibbles[1] as u8,
-                y: nibbles[2] as u8,
-            };
+            state.set_index_register(0x300);
+            state.execute_opcode(0xF365).unwrap();
+            assert_eq!(state.index_register(), expected_i);
         }
+    }
 
-        if nibbles[0] == 6 {
-            return Instruction::MovConst {
-                x: nibbles[1] as u8,
-                nn: Instruction::combine_nibbles(&nibbles[2..]) as u8,
-            };
+    #[test]
+    fn max_wait_spins_test() {
+        let mut state = debug_state();
+        state.initialize(&[0xF0, 0x0A], &DEFAULT_FONT);
+        state.set_max_wait_spins(Some(3));
+        assert_eq!(state.execute(), Ok(StepOutcome::Executed));
+        assert_eq!(state.execute(), Ok(StepOutcome::Executed));
+        assert_eq!(state.execute(), Ok(StepOutcome::StalledOnKey));
+        assert_eq!(state.execute(), Ok(StepOutcome::StalledOnKey));
+        assert_eq!(state.pc, 0x200);
+
+        // other instructions restart the count
+        state.execute_opcode(0x6001).unwrap();
+        assert_eq!(state.execute(), Ok(StepOutcome::Executed));
+
+        state.set_max_wait_spins(None);
+        for _ in 0..10 {
+            assert_eq!(state.execute(), Ok(StepOutcome::Executed));
         }
+    }
 
-        if nibbles[0] == 7 {
-            return Instruction::AddConst {
-                x: nibbles[1] as u8,
-                nn: Instruction::combine_nibbles(&nibbles[2..]) as u8,
-            };
+    #[test]
+    fn from_rom_file_test() {
+        let path = std::env::temp_dir().join(format!("chip8-lib-from-rom-file-{}.ch8", std::process::id()));
+        // draw the '0' glyph
+        std::fs::write(&path, [0xA0, 0x50, 0xD0, 0x05]).unwrap();
+        let result = State::from_rom_file(&path, Quirks::default());
+        std::fs::remove_file(&path).unwrap();
+        let (mut state, peripherals) = result.unwrap();
+        state.execute().unwrap();
+        state.execute().unwrap();
+        assert!(peripherals.display.lock().unwrap().get_pixel(0, 0));
+
+        assert!(State::from_rom_file(std::env::temp_dir().join("chip8-lib-missing.ch8"), Quirks::default()).is_err());
+    }
+
+    #[test]
+    fn quirks_getter_test() {
+        let mut state = debug_state();
+        assert_eq!(state.quirks(), Quirks::default());
+        state.set_quirks(Quirks::super_chip());
+        assert_eq!(state.quirks(), Quirks::super_chip());
+        assert!(state.quirks().lores_dxy0_draws_16_rows);
+        assert_eq!(state.quirks().load_store_increment, IndexIncrement::None);
+        assert_ne!(state.quirks(), Quirks::cosmac_vip());
+    }
+
+    #[test]
+    fn draw_info_test() {
+        let mut state = debug_state();
+        state.initialize(&[0xA0, 0x50, 0x60, 0x3C, 0x61, 0x1E, 0xD0, 0x15], &DEFAULT_FONT);
+        for _ in 0..3 {
+            state.execute().unwrap();
         }
+        // the '0' glyph at (60, 30): 2 of 5 rows are visible and the right half of the 8 pixel row is cut off
+        assert_eq!(
+            state.execute(),
+            Ok(StepOutcome::Drew(DrawInfo {
+                collided: false,
+                rows_drawn: 2,
+                clipped: true,
+            }))
+        );
 
-        if nibbles[0] == 8 {
-            let x = nibbles[1] as u8;
-            let y = nibbles[2] as u8;
-            if nibbles[3] == 0 {
-                return Instruction::Mov { x, y };
-            }
+        assert_eq!(
+            state.execute_opcode(0xD225),
+            Ok(StepOutcome::Drew(DrawInfo {
+                collided: false,
+                rows_drawn: 5,
+                clipped: false,
+            }))
+        );
 
-            if nibbles[3] == 1 {
-                return Instruction::Or { x, y };
-            }
+        // V0 = 70 is outside of the display: wrapped to x = 6 or not drawn at all when clipping
+        for (sprite_start, rows_drawn, clipped) in [(SpriteStart::Wrap, 5, false), (SpriteStart::Clip, 0, true)] {
+            let mut buffer = DisplayBuffer::new();
+            buffer.set_sprite_start(sprite_start);
+            let (mut state, peripherals) = State::from_buffers(buffer, CountdownTimer::new(), CountdownBeeper::new(), KeypadState::new());
+            state.initialize(&[0xA0, 0x50, 0x60, 0x46, 0xD0, 0x15], &DEFAULT_FONT);
+            state.execute().unwrap();
+            state.execute().unwrap();
+            assert_eq!(
+                state.execute(),
+                Ok(StepOutcome::Drew(DrawInfo {
+                    collided: false,
+                    rows_drawn,
+                    clipped,
+                }))
+            );
+            assert_eq!(peripherals.display.lock().unwrap().get_pixel(6, 0), !clipped);
+        }
 
-            if nibbles[3] == 2 {
-                return Instruction::And { x, y };
+        // a display that does not wrap the start position reports it as is
+        struct Unwrapped;
+        impl Display for Unwrapped {
+            fn modify(&mut self, _sprite: &[u8], _n: u8, _x: u8, _y: u8) -> bool {
+                false
             }
 
-            if nibbles[3] == 3 {
-                return Instruction::Xor { x, y };
+            fn width(&self) -> usize {
+                64
             }
 
-            if nibbles[3] == 4 {
-                return Instruction::Add { x, y };
+            fn height(&self) -> usize {
+                32
             }
 
-            if nibbles[3] == 5 {
-                return Instruction::SubXY { x, y };
-            }
+            fn clear(&mut self) {}
 
-            if nibbles[3] == 6 {
-                return Instruction::RightShift { x, y };
+            fn sprite_origin(&self, x: u8, y: u8) -> Option<(usize, usize)> {
+                Some((x as usize, y as usize))
             }
+        }
+        let mut state = Core::new(Unwrapped, NullTimer, NullBeeper, NullKeypad);
+        // the '0' glyph at (70, 40)
+        state.initialize(&[0xA0, 0x50, 0x60, 0x46, 0x61, 0x28, 0xD0, 0x15], &DEFAULT_FONT);
+        for _ in 0..3 {
+            state.execute().unwrap();
+        }
+        assert_eq!(
+            state.execute(),
+            Ok(StepOutcome::Drew(DrawInfo {
+                collided: false,
+                rows_drawn: 0,
+                clipped: true,
+            }))
+        );
+    }
 
-            if nibbles[3] == 7 {
-                return Instruction::SubYX { x, y };
-            }
+    #[test]
+    fn instruction_builder_test() {
+        assert_eq!(Instruction::mov_const(0xA, 0x2A).encode(), Some(0x6A2A));
+        assert_eq!(Instruction::jump(0x345).encode(), Some(0x1345));
+        assert_eq!(Instruction::draw(1, 2, 5).encode(), Some(0xD125));
+        assert_eq!(Instruction::left_shift(3, 4).encode(), Some(0x834E));
+        assert_eq!(Instruction::reg_load(7).encode(), Some(0xF765));
+        assert_eq!(Instruction::cls().encode(), Some(0x00E0));
+        assert_eq!(Instruction::Invalid.encode(), None);
+        assert_eq!(Instruction::jump(0x1000).encode(), None);
+        assert_eq!(Instruction::mov(0x10, 0).encode(), None);
 
-            if nibbles[3] == 0xE {
-                return Instruction::LeftShift { x, y };
+        // every valid opcode round-trips
+        for op_code in 0..=0xFFFF {
+            let instruction = Instruction::decode(op_code);
+            if instruction != Instruction::Invalid {
+                assert_eq!(instruction.encode(), Some(op_code), "{:#06x}", op_code);
             }
         }
 
-        if nibbles[0] == 9 {
-            if nibbles[3] == 0 {
-                return Instruction::SkipNeq {
-                    x: nibbles[1] as u8,
-                    y: nibbles[2] as u8,
-                };
-            }
+        let mut state = debug_state();
+        state.execute_opcode(Instruction::mov_const(0, 5).encode().unwrap()).unwrap();
+        assert_eq!(state.gp_registers[0], 5);
+    }
+
+    #[test]
+    fn draw_interference_hook_test() {
+        let count = Arc::new(Mutex::new(0));
+        let hook_count = count.clone();
+        let mut state = debug_state();
+        state.set_draw_interference_hook(Box::new(move || *hook_count.lock().unwrap() += 1));
+        // DRW, CLS, DRW
+        state.initialize(&[0xD0, 0x05, 0x00, 0xE0, 0xD0, 0x01], &DEFAULT_FONT);
+        state.execute().unwrap();
+        assert_eq!(*count.lock().unwrap(), 1);
+        state.execute().unwrap();
+        assert_eq!(*count.lock().unwrap(), 1);
+        state.execute().unwrap();
+        assert_eq!(*count.lock().unwrap(), 2);
+    }
+
+    #[test]
+    fn instruction_at_test() {
+        let mut state = debug_state();
+        state.initialize(&halt_program(), &DEFAULT_FONT);
+        state.write_bytes(0x300, &[0xD1, 0x25]);
+        assert_eq!(state.instruction_at(0x300), Instruction::Draw { x: 1, y: 2, n: 5 });
+        assert_eq!(state.instruction_at(0x200), Instruction::Jump { nnn: 0x200 });
+        state.write_bytes(0xFFE, &[0x12, 0x00]);
+        assert_eq!(state.instruction_at(0xFFE), Instruction::Jump { nnn: 0x200 });
+        assert_eq!(state.instruction_at(0xFFF), Instruction::Invalid);
+        assert_eq!(state.instruction_at(0x5000), Instruction::Invalid);
+        assert_eq!(state.instruction_at(usize::MAX), Instruction::Invalid);
+    }
+
+    #[test]
+    fn skip_long_instructions_test() {
+        // SE V0, 0 skips F000 NNNN, the NNNN is 0x1234
+        let rom = [0x30, 0x00, 0xF0, 0x00, 0x12, 0x34, 0x60, 0x01];
+        for (quirk, expected_pc) in [(false, 0x204), (true, 0x206)] {
+            let mut state = debug_state();
+            state.set_quirks(Quirks {
+                skip_long_instructions: quirk,
+                ..Quirks::default()
+            });
+            state.initialize(&rom, &DEFAULT_FONT);
+            state.execute().unwrap();
+            assert_eq!(state.pc, expected_pc);
         }
 
-        if nibbles[0] == 0xA {
-            return Instruction::MovI {
-                nnn: Instruction::combine_nibbles(&nibbles[1..]),
-            };
+        // the key skips as well, and ordinary instructions are still skipped by 2
+        let mut state = debug_state();
+        state.set_quirks(Quirks {
+            skip_long_instructions: true,
+            ..Quirks::default()
+        });
+        state.initialize(&[0xE0, 0xA1, 0xF0, 0x00, 0x12, 0x34, 0x30, 0x00, 0x60, 0x01], &DEFAULT_FONT);
+        state.execute().unwrap();
+        assert_eq!(state.pc, 0x206);
+        state.execute().unwrap();
+        assert_eq!(state.pc, 0x20A);
+    }
+
+    #[test]
+    fn present_dirty_test() {
+        let mut buffer = DisplayBuffer::new();
+        buffer.modify(&[0b1100_0000], 1, 10, 5);
+        buffer.present_dirty(|_, _, _| ());
+
+        // flips (10, 5) off and (12, 5) on, (11, 5) stays on
+        buffer.modify(&[0b1010_0000], 1, 10, 5);
+        let mut changed = Vec::new();
+        buffer.present_dirty(|x, y, on| changed.push((x, y, on)));
+        assert_eq!(changed, vec![(10, 5, false), (12, 5, true)]);
+
+        let mut calls = 0;
+        buffer.present_dirty(|_, _, _| calls += 1);
+        assert_eq!(calls, 0);
+    }
+
+    #[test]
+    fn validate_rom_test() {
+        // draw the '0' glyph and halt
+        assert_eq!(validate_rom(&[0xA0, 0x50, 0xD0, 0x05, 0x12, 0x04]), vec![]);
+
+        let mut rng = SeededRng::new(7);
+        let garbage: Vec<u8> = (0..4001).map(|_| rng.next_byte()).collect();
+        let warnings = validate_rom(&garbage);
+        assert_eq!(warnings[0], RomWarning::TooLarge { size: 4001, max: 0xE00 });
+        assert_eq!(warnings[1], RomWarning::OddLength);
+        let invalid = warnings.iter().filter(|w| matches!(w, RomWarning::InvalidOpcode { .. })).count();
+        assert!(invalid > 100, "{}", invalid);
+
+        assert_eq!(
+            validate_rom(&[0xFF, 0xFF]),
+            vec![RomWarning::InvalidOpcode { address: 0x200, op_code: 0xFFFF }, RomWarning::NoInstructions]
+        );
+        assert_eq!(validate_rom(&[]), vec![RomWarning::NoInstructions]);
+    }
+
+    #[test]
+    fn font_bytes_test() {
+        let mut state = debug_state();
+        state.initialize(&halt_program(), &DEFAULT_FONT);
+        assert_eq!(state.font_bytes(), DEFAULT_FONT);
+
+        let mut font = DEFAULT_FONT;
+        font[0] = 0x60;
+        state.initialize_with_font_at(&halt_program(), &font, 0x100);
+        assert_eq!(state.font_bytes(), font);
+    }
+
+    #[test]
+    fn keypad_latch_test() {
+        let (mut state, peripherals) = buffer_state();
+        // V0 = 5, skip if key V0 is pressed, V1 = 1, wait for a key into V2
+        state.initialize(&[0x60, 0x05, 0xE0, 0x9E, 0x61, 0x01, 0xF2, 0x0A], &DEFAULT_FONT);
+        peripherals.keypad.lock().unwrap().press(5);
+        state.set_keypad_latch(true);
+        state.execute().unwrap();
+
+        // the key is released mid-frame, the skip still sees it
+        peripherals.keypad.lock().unwrap().release(5);
+        state.execute().unwrap();
+        assert_eq!(state.pc, 0x206);
+        state.execute().unwrap();
+        assert_eq!(state.gp_registers[2], 5);
+
+        // the next frame sees the release
+        state.tick_timers();
+        state.pc = 0x206;
+        state.execute().unwrap();
+        assert_eq!(state.pc, 0x206);
+    }
+
+    #[test]
+    fn disassemble_map_test() {
+        let map = disassemble_map(&[0x60, 0x05, 0xF0, 0x00, 0x12, 0x00], 0x200);
+        let entries: Vec<(usize, &str)> = map.iter().map(|(addr, line)| (*addr, line.as_str())).collect();
+        assert_eq!(entries, vec![(0x200, "LD V0, 0x05"), (0x202, "DB 0xf000"), (0x204, "JP 0x200")]);
+    }
+
+    #[test]
+    fn min_sound_timer_test() {
+        let (mut state, peripherals) = buffer_state();
+        state.set_min_sound_timer(2);
+        state.gp_registers[0] = 1;
+        state.execute_opcode(0xF018).unwrap();
+        assert!(!peripherals.sound_timer.lock().unwrap().is_beeping());
+
+        state.gp_registers[0] = 2;
+        state.execute_opcode(0xF018).unwrap();
+        assert!(peripherals.sound_timer.lock().unwrap().is_beeping());
+
+        // a short value still stops a running beeper
+        state.gp_registers[0] = 1;
+        state.execute_opcode(0xF018).unwrap();
+        assert!(!peripherals.sound_timer.lock().unwrap().is_beeping());
+    }
+
+    #[test]
+    fn step_with_timers_test() {
+        let clock = Clock { cpu_hz: 700, timer_hz: 60 };
+        let mut state = debug_state();
+        state.initialize(&halt_program(), &DEFAULT_FONT);
+        state.delay_timer.lock().unwrap().set(100);
+        for _ in 0..700 {
+            state.step_with_timers(&clock).unwrap();
+        }
+        assert_eq!(state.delay_timer.lock().unwrap().get(), 40);
+        for _ in 0..12 {
+            state.step_with_timers(&clock).unwrap();
         }
+        assert_eq!(state.delay_timer.lock().unwrap().get(), 39);
+    }
 
-        if nibbles[0] == 0xB {
-            return Instruction::JumpIndexed {
-                nnn: Instruction::combine_nibbles(&nibbles[1..]),
-            };
+    #[test]
+    fn from_grid_test() {
+        // the left half is lit
+        let pixels: Vec<bool> = (0..64 * 32).map(|i| i % 64 < 32).collect();
+        let buffer = DisplayBuffer::from_grid(64, 32, pixels).unwrap();
+        assert!(buffer.get_pixel(31, 31) && !buffer.get_pixel(32, 0));
+
+        let (mut state, _) = State::from_buffers(buffer, CountdownTimer::new(), CountdownBeeper::new(), KeypadState::new());
+        state.set_clear_display_on_init(false);
+        state.initialize(&[0xA0, 0x50, 0x60, 0x28, 0xD0, 0x05, 0x60, 0x1C, 0xD0, 0x05], &DEFAULT_FONT);
+        // the right half is free
+        for _ in 0..3 {
+            state.execute().unwrap();
         }
+        assert_eq!(state.gp_registers[0xF], 0);
+        // across the edge at x = 28
+        state.execute().unwrap();
+        state.execute().unwrap();
+        assert_eq!(state.gp_registers[0xF], 1);
 
-        if nibbles[0] == 0xC {
-            return Instruction::Rand {
-                x: nibbles[1] as u8,
-                nn: Instruction::combine_nibbles(&nibbles[2..]) as u8,
-            };
+        assert_eq!(
+            DisplayBuffer::from_grid(64, 32, vec![false; 10]).err(),
+            Some(BufferSizeError { expected: 2048, actual: 10 })
+        );
+    }
+
+    #[test]
+    fn vf_order_test() {
+        // VF = 0xFF, V1 = 0x03: VF += V1 overflows to 0x02 with carry 1, VF >>= 1 gives 0x7F with flag 1
+        let program = [0x6F, 0xFF, 0x61, 0x03, 0x8F, 0x14, 0x6F, 0xFF, 0x8F, 0x06];
+        let run = |quirks: Quirks| {
+            let (mut state, _) = buffer_state();
+            state.set_quirks(quirks);
+            state.initialize(&program, &DEFAULT_FONT);
+            for _ in 0..3 {
+                state.execute().unwrap();
+            }
+            let after_add = state.gp_registers[0xF];
+            state.execute().unwrap();
+            state.execute().unwrap();
+            (after_add, state.gp_registers[0xF])
+        };
+
+        // the flag wins
+        assert_eq!(run(Quirks::default()), (1, 1));
+        // the result wins
+        assert_eq!(run(Quirks { vf_order_legacy: true, ..Quirks::default() }), (0x02, 0x7F));
+    }
+
+    #[test]
+    fn to_rgba_test() {
+        let mut buffer = DisplayBuffer::new();
+        buffer.modify(&[0b10000000], 1, 2, 1);
+        let rgba = buffer.to_rgba([0xFF, 0xB0, 0x00, 0xFF], [0x10, 0x10, 0x10, 0xFF]);
+        assert_eq!(rgba.len(), 64 * 32 * 4);
+        let at = |x: usize, y: usize| &rgba[(y * 64 + x) * 4..(y * 64 + x) * 4 + 4];
+        assert_eq!(at(2, 1), [0xFF, 0xB0, 0x00, 0xFF]);
+        assert_eq!(at(3, 1), [0x10, 0x10, 0x10, 0xFF]);
+    }
+
+    #[test]
+    fn lit_pixel_count_test() {
+        let mut buffer = DisplayBuffer::new();
+        assert_eq!(buffer.lit_pixel_count(), 0);
+        // the font "0" has 14 pixels
+        buffer.modify(&DEFAULT_FONT[0..5], 5, 10, 10);
+        assert_eq!(buffer.lit_pixel_count(), 14);
+        // 0xFF over the top row 0xF0 turns 4 pixels off and 4 on
+        buffer.modify(&[0xFF], 1, 10, 10);
+        assert_eq!(buffer.lit_pixel_count(), 14);
+        buffer.modify(&DEFAULT_FONT[1..5], 4, 10, 11);
+        assert_eq!(buffer.lit_pixel_count(), 4);
+    }
+
+    #[test]
+    fn wide_sprite_at_end_of_64k_test() {
+        let (mut state, peripherals) = buffer_state();
+        state.initialize(&[0x12, 0x00], &DEFAULT_FONT);
+        state.set_memory_size(MemorySize::XoChip);
+        state.set_hires();
+        state.write_bytes(0xFFE0, &[0xFF; 32]);
+
+        // 32 bytes fit exactly
+        state.set_index_register(0xFFE0);
+        match state.execute_opcode(0xD010).unwrap() {
+            StepOutcome::Drew(info) => assert_eq!(info.rows_drawn, 16),
+            outcome => panic!("unexpected {:?}", outcome),
         }
+        assert_eq!(peripherals.display.lock().unwrap().lit_pixel_count(), 256);
 
-        if nibbles[0] == 0xD {
-            return Instruction::Draw {
-                x: nibbles[1] as u8,
-                y: nibbles[2] as u8,
-                n: nibbles[3] as u8,
-            };
+        // only 16 bytes left, the sprite is cut off after 8 rows
+        state.execute_opcode(0x00E0).unwrap();
+        state.set_index_register(0xFFF0);
+        match state.execute_opcode(0xD010).unwrap() {
+            StepOutcome::Drew(info) => assert_eq!(info.rows_drawn, 8),
+            outcome => panic!("unexpected {:?}", outcome),
         }
+        assert_eq!(peripherals.display.lock().unwrap().lit_pixel_count(), 128);
+    }
 
-        if nibbles[0] == 0xE {
-            let x = nibbles[1] as u8;
-            if nibbles[2] == 9 && nibbles[3] == 0xE {
-                return Instruction::SkipKeyEq { x };
+    #[test]
+    fn extension_handler_test() {
+        let mut state = strict_debug_state();
+        state.initialize(&[0x0F, 0xFF, 0x01, 0x23], &DEFAULT_FONT);
+        state.set_extension_handler(Box::new(|state, op_code| {
+            if op_code == 0x0FFF {
+                state.gp_registers[0] = 0xAA;
+                true
+            } else {
+                false
             }
+        }));
+        assert_eq!(state.execute(), Ok(StepOutcome::Executed));
+        assert_eq!(state.gp_registers[0], 0xAA);
+        assert_eq!(state.pc, 0x202);
+        assert_eq!(state.execute(), Err(ExecutionError::InvalidOpcode(0x0123)));
+        assert_eq!(state.pc, 0x202);
+    }
 
-            if nibbles[2] == 0xA && nibbles[3] == 1 {
-                return Instruction::SkipKeyNeq { x };
+    #[test]
+    fn input_replay_test() {
+        // V1 counts the steps with key 5 pressed and mixes in random numbers
+        let program = [0x60, 0x05, 0xE0, 0x9E, 0x12, 0x08, 0x71, 0x01, 0xC2, 0xFF, 0x81, 0x24, 0x12, 0x02];
+        let run = |state: &mut State, keypad: &Mutex<KeypadState>| {
+            let mut frames = Vec::new();
+            for frame in 0..12 {
+                if frame % 3 == 0 {
+                    keypad.lock().unwrap().press(5);
+                } else {
+                    keypad.lock().unwrap().release(5);
+                }
+                frames.push(state.trace_hash(7));
+                state.tick_timers();
             }
+            (frames, state.gp_registers)
+        };
+
+        let (state, peripherals) = buffer_state();
+        let mut state = state.with_rng_seed(42);
+        state.initialize(&program, &DEFAULT_FONT);
+        state.set_keypad_latch(true);
+        state.start_recording();
+        let recorded = run(&mut state, &peripherals.keypad);
+        let inputs = state.stop_recording();
+        // the key pressed in frame 0 is latched at its end and seen in frame 1
+        assert_eq!(inputs.len(), 13);
+        assert_eq!(inputs[..5], [0, 1 << 5, 0, 0, 1 << 5]);
+
+        // the host keypad is ignored while replaying
+        let (state, peripherals) = buffer_state();
+        let mut state = state.with_rng_seed(42);
+        state.initialize(&program, &DEFAULT_FONT);
+        state.set_keypad_latch(true);
+        peripherals.keypad.lock().unwrap().press(5);
+        state.replay(&inputs);
+        assert_eq!(run(&mut state, &Mutex::new(KeypadState::new())), recorded);
+    }
+
+    #[test]
+    fn font_glyph_test() {
+        let mut state = debug_state();
+        state.initialize(&[0x12, 0x00], &DEFAULT_FONT);
+        let (o, x) = (false, true);
+        assert_eq!(
+            state.font_glyph(0),
+            [
+                [x, x, x, x, o, o, o, o],
+                [x, o, o, x, o, o, o, o],
+                [x, o, o, x, o, o, o, o],
+                [x, o, o, x, o, o, o, o],
+                [x, x, x, x, o, o, o, o],
+            ]
+        );
+        assert_eq!(state.font_glyph(0x1F), state.font_glyph(0xF));
+    }
+
+    #[test]
+    fn program_write_hook_test() {
+        let mut state = debug_state();
+        // V0 = 0xAB, V1 = 0xCD, I = 0x206, dump V0..V1 over the last instruction, jump
+        state.initialize(&[0x60, 0xAB, 0x61, 0xCD, 0xA2, 0x06, 0xF1, 0x55, 0x12, 0x08], &DEFAULT_FONT);
+        let writes = Arc::new(Mutex::new(Vec::new()));
+        let hook_writes = writes.clone();
+        state.set_program_write_hook(Box::new(move |addr, value| hook_writes.lock().unwrap().push((addr, value))));
+        for _ in 0..4 {
+            state.execute().unwrap();
         }
+        assert_eq!(*writes.lock().unwrap(), [(0x206, 0xAB), (0x207, 0xCD)]);
+        assert_eq!(state.memory()[0x206..0x208], [0xAB, 0xCD]);
 
-        if nibbles[0] == 0xF {
-            let x = nibbles[1] as u8;
-            if nibbles[2] == 0 && nibbles[3] == 7 {
-                return Instruction::GetDelayTimer { x };
-            }
+        // behind the program
+        state.execute_opcode(0xA30A).unwrap();
+        state.execute_opcode(0xF133).unwrap();
+        assert_eq!(writes.lock().unwrap().len(), 2);
+    }
 
-            if nibbles[2] == 0 && nibbles[3] == 0xA {
-                return Instruction::WaitKey { x };
-            }
+    #[test]
+    fn fetch_execute_decoded_test() {
+        let program = [0x60, 0x05, 0xA2, 0x08, 0xF0, 0x33, 0x22, 0x0A, 0x00, 0x00, 0x70, 0xFF, 0x00, 0xEE];
+        let mut stages = debug_state();
+        stages.initialize(&program, &DEFAULT_FONT);
+        let mut whole = debug_state();
+        whole.initialize(&program, &DEFAULT_FONT);
+        stages.set_opcode_logging(true);
+        whole.set_opcode_logging(true);
 
-            if nibbles[2] == 1 && nibbles[3] == 5 {
-                return Instruction::SetDelayTimer { x };
-            }
+        for _ in 0..6 {
+            let op_code = stages.fetch().unwrap();
+            let instruction = Instruction::decode(op_code);
+            assert_eq!(stages.execute_decoded(instruction), whole.execute());
+            assert_eq!((stages.pc, stages.gp_registers, stages.index_reg), (whole.pc, whole.gp_registers, whole.index_reg));
+        }
+        assert_eq!(stages.opcode_log(), whole.opcode_log());
+        assert_eq!(stages.memory(), whole.memory());
 
-            if nibbles[2] == 1 && nibbles[3] == 8 {
-                return Instruction::SetSoundTimer { x };
-            }
+        // a patched instruction is logged with its own opcode
+        stages.initialize(&program, &DEFAULT_FONT);
+        stages.fetch().unwrap();
+        stages.execute_decoded(Instruction::MovConst { x: 1, nn: 7 }).unwrap();
+        assert_eq!(stages.gp_registers[1], 7);
+        assert_eq!(stages.opcode_log().last(), Some(&(0x200, 0x6107)));
+    }
 
-            if nibbles[2] == 1 && nibbles[3] == 0xE {
-                return Instruction::AddI { x };
-            }
+    #[test]
+    fn reg_dump_wrap_test() {
+        let mut state = debug_state();
+        state.initialize(&[0x12, 0x00], &DEFAULT_FONT);
+        for (i, register) in state.gp_registers.iter_mut().enumerate() {
+            *register = 0xA0 + i as u8;
+        }
+        state.set_index_register(0xFF8);
+        state.execute_opcode(0xFF55).unwrap();
+        // V0..V7 at the end of memory, V8..VF at the start
+        assert_eq!(state.memory()[0xFF8..0x1000], [0xA0, 0xA1, 0xA2, 0xA3, 0xA4, 0xA5, 0xA6, 0xA7]);
+        assert_eq!(state.memory()[0x000..0x008], [0xA8, 0xA9, 0xAA, 0xAB, 0xAC, 0xAD, 0xAE, 0xAF]);
 
-            if nibbles[2] == 2 && nibbles[3] == 9 {
-                return Instruction::SetFontI { x };
-            }
+        state.gp_registers = [0; 16];
+        state.execute_opcode(0xFF65).unwrap();
+        assert_eq!(state.gp_registers[0x7], 0xA7);
+        assert_eq!(state.gp_registers[0xF], 0xAF);
 
-            if nibbles[2] == 3 && nibbles[3] == 3 {
-                return Instruction::BCD { x };
-            }
+        let mut strict = strict_debug_state();
+        strict.initialize(&[0x12, 0x00], &DEFAULT_FONT);
+        strict.set_index_register(0xFF8);
+        assert_eq!(strict.execute_opcode(0xFF55), Err(ExecutionError::MemoryOutOfBounds(0x1000)));
+        assert_eq!(strict.memory()[0xFF8], 0);
+    }
 
-            if nibbles[2] == 5 && nibbles[3] == 5 {
-                return Instruction::RegDump { x };
+    #[test]
+    fn draw_vf_only_on_collision_test() {
+        // VF = 0x42, draw the font "0" twice at the same position, then once somewhere else
+        let program = [0x6F, 0x42, 0xA0, 0x50, 0xD0, 0x05, 0xD0, 0x05, 0x61, 0x10, 0xD1, 0x15];
+        let run = |quirks: Quirks| {
+            let (mut state, _) = buffer_state();
+            state.set_quirks(quirks);
+            state.initialize(&program, &DEFAULT_FONT);
+            let mut vf = Vec::new();
+            for _ in 0..6 {
+                state.execute().unwrap();
+                vf.push(state.gp_registers[0xF]);
             }
+            vf[2..].to_vec()
+        };
 
-            if nibbles[2] == 6 && nibbles[3] == 5 {
-                return Instruction::RegLoad { x };
+        // no collision, collision, (V1 = 0x10), no collision
+        assert_eq!(run(Quirks::default()), [0, 1, 1, 0]);
+        assert_eq!(run(Quirks { draw_sets_vf_only_on_collision: true, ..Quirks::default() }), [0x42, 1, 1, 1]);
+
+        // a draw without collision leaves VF uninitialized under the quirk, V0 = VF reads it
+        // the draw itself reads the uninitialized V0
+        for (only_on_collision, expected) in [(false, vec![0]), (true, vec![0, 0xF])] {
+            let reads = Arc::new(Mutex::new(Vec::new()));
+            let hook_reads = reads.clone();
+            let (mut state, _) = buffer_state();
+            state.set_quirks(Quirks { draw_sets_vf_only_on_collision: only_on_collision, ..Quirks::default() });
+            state.set_uninitialized_read_hook(Box::new(move |register, _| hook_reads.lock().unwrap().push(register)));
+            state.initialize(&[0xA0, 0x50, 0xD0, 0x05, 0x80, 0xF0], &DEFAULT_FONT);
+            for _ in 0..3 {
+                state.execute().unwrap();
             }
+            assert_eq!(*reads.lock().unwrap(), expected);
         }
-
-        return Instruction::Invalid;
     }
 
-    fn code_to_nibble_array(op_code: u16) -> [u16; 4] {
-        [
-            (op_code & 0xF000) >> 12,
-            (op_code & 0x0F00) >> 8,
-            (op_code & 0x00F0) >> 4,
-            op_code & 0x000F,
-        ]
+    #[test]
+    fn find_sprite_references_test() {
+        let rom = [
+            0xA2, 0x14, // I = 0x214
+            0x60, 0x08, // V0 = 8
+            0xD0, 0x14, // draw 4 rows
+            0xD0, 0x14, // again
+            0xA2, 0x18, // I = 0x218
+            0x12, 0x0E, // jump, the draw below does not use 0x218
+            0xD0, 0x13,
+            0xA2, 0x10, // I = 0x210
+            0xD0, 0x10, // DXY0
+            0x12, 0x00,
+            0x3C, 0x42, 0x42, 0x3C, // sprite
+        ];
+        assert_eq!(find_sprite_references(&rom, 0x200), [(0x210, 0), (0x214, 4)]);
+        assert_eq!(find_sprite_references(&[0xD0, 0x15], 0x200), []);
     }
 
-    fn combine_nibbles(nibbles: &[u16]) -> u16 {
-        let mut combined = 0;
-        for (i, nibble) in nibbles.iter().enumerate() {
-            combined = combined | (*nibble << ((nibbles.len() - 1 - i) * 4));
+    #[test]
+    fn owned_timers_test() {
+        let mut state = State::with_owned_timers(Arc::new(Mutex::new(NullDisplay)), Arc::new(Mutex::new(NullKeypad)));
+        // DT = ST = 10, V1 = DT
+        state.initialize(&[0x60, 0x0A, 0xF0, 0x15, 0xF0, 0x18, 0xF1, 0x07], &DEFAULT_FONT);
+        for _ in 0..3 {
+            state.execute().unwrap();
         }
-        combined
+        assert!(state.should_beep());
+        for _ in 0..4 {
+            state.tick_timers();
+        }
+        state.execute().unwrap();
+        assert_eq!(state.gp_registers[1], 6);
+        // the timers are stored in the state, not behind a lock
+        let timer: &CountdownTimer = &state.delay_timer;
+        assert_eq!(timer.get(), 6);
+
+        for _ in 0..6 {
+            state.tick_timers();
+        }
+        assert!(!state.should_beep());
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    #[test]
+    fn sound_frames_remaining_test() {
+        let mut state = State::with_owned_timers(Arc::new(Mutex::new(NullDisplay)), Arc::new(Mutex::new(NullKeypad)));
+        state.initialize(&[0x60, 0x1E, 0xF0, 0x18], &DEFAULT_FONT);
+        assert_eq!(state.sound_frames_remaining(), 0);
+        state.execute().unwrap();
+        state.execute().unwrap();
+        assert_eq!(state.sound_frames_remaining(), 30);
+        state.tick_timers();
+        assert_eq!(state.sound_frames_remaining(), 29);
+    }
 
     #[test]
-    fn it_works() {
-        let result = add(2, 2);
-        assert_eq!(result, 4);
+    fn set_rng_test() {
+        struct Canned(Vec<u8>);
+        impl RandomSource for Canned {
+            fn next_byte(&mut self) -> u8 {
+                self.0.remove(0)
+            }
+        }
+
+        let mut state = debug_state().with_rng_seed(1);
+        state.initialize(&[0xC0, 0xFF, 0xC0, 0xFF, 0xC0, 0xFF, 0xC0, 0x0F], &DEFAULT_FONT);
+        state.execute().unwrap();
+        state.set_rng(Box::new(Canned(vec![0x12, 0x34, 0xAB])));
+        let mut values = Vec::new();
+        for _ in 0..3 {
+            state.execute().unwrap();
+            values.push(state.gp_registers[0]);
+        }
+        // the mask still applies
+        assert_eq!(values, [0x12, 0x34, 0x0B]);
     }
 
     #[test]
-    fn u8_to_bool_test() {
-        let byte: u8 = 0b10110011;
-        let array = u8_to_bool_array(byte);
-        assert_eq!(array, [true, false, true, true, false, false, true, true]);
-        let byte: u8 = 0b00000000;
-        let array = u8_to_bool_array(byte);
-        assert_eq!(
-            array,
-            [false, false, false, false, false, false, false, false]
-        );
-        let byte: u8 = 0b11111111;
-        let array = u8_to_bool_array(byte);
-        assert_eq!(array, [true, true, true, true, true, true, true, true]);
+    fn backtrace_test() {
+        let mut state = debug_state();
+        // call 0x206, which calls 0x20a
+        state.initialize(&[0x22, 0x06, 0x12, 0x00, 0x00, 0x00, 0x22, 0x0A, 0x00, 0xEE, 0x60, 0x01, 0x00, 0xEE], &DEFAULT_FONT);
+        assert_eq!(state.backtrace(), "#0 0x200");
+        state.execute().unwrap();
+        state.execute().unwrap();
+        state.execute().unwrap();
+        assert_eq!(state.backtrace(), "#0 0x20c  #1 0x208  #2 0x202");
     }
 
     